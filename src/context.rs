@@ -3,7 +3,6 @@ use std::iter;
 use std::rc;
 use std::rc::Rc;
 
-use rand::rngs::ThreadRng;
 use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::rect::Point;
@@ -13,6 +12,8 @@ use sdl2::render::Texture;
 use sdl2::render::TextureAccess;
 use sdl2::render::TextureCreator;
 use sdl2::rwops::RWops;
+use sdl2::image::SaveSurface;
+use sdl2::surface::Surface;
 use sdl2::ttf::Font;
 use sdl2::ttf::Sdl2TtfContext;
 use sdl2::video::Window;
@@ -20,16 +21,30 @@ use sdl2::video::WindowContext;
 use crate::datatype::*;
 use crate::toolbox::*;
 use crate::world_grid::*;
+use crate::material::*;
 
 
+// Closest the camera can get, in window pixels per cell
+const MAX_ZOOM: f32 = 32.0;
+
+// What part of the world the window shows
+#[derive(Clone, Copy)]
+pub struct Camera {
+    // Window pixels per cell
+    pub zoom: f32,
+    // World position, in cells, at the top left corner of the window
+    pub offset: Vector2<f32>,
+}
+
 pub struct Context<'a> {
     pub canvas: &'a mut Canvas<Window>,
     pub world: World,
     pub toolbox: ToolBox,
-    pub rng: ThreadRng,
-    pub point_size: usize,
+    pub camera: Camera,
     pub tick_counter: usize,
     pub font: Font<'a, 'a>,
+    // Rooms, their hotness and the cells updated each frame, on top of the world
+    pub debug_overlay: bool,
 
     texture_creator: &'a TextureCreator<WindowContext>,
     cells_texture: Texture<'a>,
@@ -40,12 +55,10 @@ impl<'a> Context<'a> {
     pub fn new(
         canvas: &'a mut Canvas<Window>,
         world: World,
-        point_size: usize,
         ttf: &'a Sdl2TtfContext,
         texture_creator: &'a TextureCreator<WindowContext>,
     ) -> Self {
-        let mut toolbox = ToolBox::new();
-        let mut rng = rand::thread_rng();
+        let mut toolbox = ToolBox::new(world.materials());
         let world_size = Vector2 {
             x: world.width() as i32,
             y: world.height() as i32,
@@ -74,25 +87,211 @@ impl<'a> Context<'a> {
             canvas,
             world,
             toolbox,
-            rng,
-            point_size,
+            camera: Camera { zoom: 1.0, offset: Vector2 { x: 0.0, y: 0.0 } },
             tick_counter: 0,
             font,
+            debug_overlay: false,
             texture_creator: texture_creator,
             cells_texture: cells_texture,
         };
+        ret.reset_camera();
 
         return ret;
     }
 
+    // Zoom that fits the whole world in the window
+    fn fit_zoom(&self) -> f32 {
+        let (window_width, window_height) = self.canvas.output_size().unwrap();
+        (window_width as f32 / self.world.width() as f32).min(window_height as f32 / self.world.height() as f32)
+    }
+
+    // Whole world in view, centred
+    pub fn reset_camera(&mut self) {
+        let (window_width, window_height) = self.canvas.output_size().unwrap();
+        let zoom = self.fit_zoom();
+        self.camera = Camera {
+            zoom: zoom,
+            offset: Vector2 {
+                x: (self.world.width() as f32 - window_width as f32 / zoom) / 2.0,
+                y: (self.world.height() as f32 - window_height as f32 / zoom) / 2.0,
+            },
+        };
+    }
+
+    // Zooms by `factor`, keeping the cell under `pos` (window pixels) where it is
+    pub fn zoom_at(&mut self, pos: Vector2<i32>, factor: f32) {
+        let zoom = (self.camera.zoom * factor).clamp(self.fit_zoom().min(1.0) / 2.0, MAX_ZOOM);
+        let world_x = self.camera.offset.x + pos.x as f32 / self.camera.zoom;
+        let world_y = self.camera.offset.y + pos.y as f32 / self.camera.zoom;
+        self.camera.zoom = zoom;
+        self.camera.offset.x = world_x - pos.x as f32 / zoom;
+        self.camera.offset.y = world_y - pos.y as f32 / zoom;
+    }
+
+    // Moves the world along with the mouse, `dx` and `dy` in window pixels
+    pub fn pan(&mut self, dx: i32, dy: i32) {
+        self.camera.offset.x -= dx as f32 / self.camera.zoom;
+        self.camera.offset.y -= dy as f32 / self.camera.zoom;
+    }
+
+    // Cell under a window position, None off the world
+    pub fn screen_to_cell(&self, pos: Vector2<i32>) -> Option<Vector2<usize>> {
+        let x = (self.camera.offset.x + pos.x as f32 / self.camera.zoom).floor();
+        let y = (self.camera.offset.y + pos.y as f32 / self.camera.zoom).floor();
+        if (x < 0.0 || y < 0.0 || x >= self.world.width() as f32 || y >= self.world.height() as f32) {
+            return None;
+        }
+        Some(Vector2 {
+            x: x as usize,
+            y: y as usize,
+        })
+    }
+
+    // Window area covered by `width` x `height` cells from (x, y), at least a pixel
+    fn cells_rect(&self, x: usize, y: usize, width: usize, height: usize) -> Rect {
+        let left = ((x as f32 - self.camera.offset.x) * self.camera.zoom).floor();
+        let top = ((y as f32 - self.camera.offset.y) * self.camera.zoom).floor();
+        let right = (((x + width) as f32 - self.camera.offset.x) * self.camera.zoom).floor();
+        let bottom = (((y + height) as f32 - self.camera.offset.y) * self.camera.zoom).floor();
+        Rect::new(
+            left as i32,
+            top as i32,
+            (right - left).max(1.0) as u32,
+            (bottom - top).max(1.0) as u32,
+        )
+    }
+
     pub fn draw_toolbox(&mut self) {
         let dy = 30;
         let mut y = 0;
-        self.draw_text(format!("Box size: {}", self.toolbox.mouse_box()).as_str() , Vector2{x:0, y: y+dy});
+        self.draw_text(
+            format!(
+                "Brush: {} {} ({}/tick)",
+                self.toolbox.brush_shape().name(), self.toolbox.mouse_box().x, self.toolbox.points_per_paint(),
+            ).as_str(),
+            Vector2{x:0, y: y+dy},
+        );
         y+=dy;
+        let tool = match self.toolbox.tool() {
+            Tool::Paint => self.world.materials().get(self.toolbox.material()).name.clone(),
+            Tool::Erase => "eraser".to_string(),
+            Tool::EraseMaterial => format!("eraser ({})", self.world.materials().get(self.toolbox.material()).name),
+            tool => format!("{} ({})", tool.name(), self.world.materials().get(self.toolbox.material()).name),
+        };
+        self.draw_text(format!("Tool: {}", tool).as_str(), Vector2{x:0, y: y+dy});
+        y+=dy;
+        let color = match self.toolbox.color_mode() {
+            ColorMode::Natural => "natural",
+            ColorMode::Palette => "palette",
+            ColorMode::Pulse => "pulse",
+        };
+        self.draw_text(format!("Color: {}", color).as_str(), Vector2{x:0, y: y+dy});
+        let swatch_color = match self.toolbox.color_mode() {
+            ColorMode::Palette => *self.toolbox.get_current_color(),
+            _ => self.world.materials().get(self.toolbox.material()).color,
+        };
+        self.draw_swatch(swatch_color, Vector2{x:200, y: y+dy+4}, true);
+        y+=dy;
+
+        // Hotbar, numbered like the keys
+        let hotbar = self.toolbox.hotbar().clone();
+        for (i, material) in hotbar.iter().enumerate() {
+            let x = i * 30;
+            let selected = self.toolbox.tool() == Tool::Paint && self.toolbox.material() == *material;
+            let color = self.world.materials().get(*material).color;
+            self.draw_swatch(color, Vector2{x: x+4, y: y+dy+4}, selected);
+            self.draw_text(format!("{}", (i+1) % 10).as_str(), Vector2{x: x+8, y: y+dy+24});
+        }
         self.draw_text("Fdp!", Vector2{x:0, y:0})
     }
 
+    // Brush cells that touch the outside, one cell square each
+    pub fn draw_brush(&mut self, center: Vector2<usize>) {
+        let cells = self.toolbox.cells(center, self.world.width(), self.world.height());
+        let covered : std::collections::HashSet<Vector2<usize>> = cells.iter().copied().collect();
+        let is_covered = |x: usize, y: usize, dx: i32, dy: i32| {
+            let nx = x as i32 + dx;
+            let ny = y as i32 + dy;
+            nx >= 0 && ny >= 0 && covered.contains(&Vector2{x: nx as usize, y: ny as usize})
+        };
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        for cell in cells.iter() {
+            let inner = is_covered(cell.x, cell.y, -1, 0) && is_covered(cell.x, cell.y, 1, 0)
+                && is_covered(cell.x, cell.y, 0, -1) && is_covered(cell.x, cell.y, 0, 1);
+            if (!inner) {
+                let rect = self.cells_rect(cell.x, cell.y, 1, 1);
+                self.canvas.fill_rect(rect).unwrap();
+            }
+        }
+    }
+
+    // The shape the current tool would draw, see-through
+    pub fn draw_shape_preview(&mut self, from: Vector2<usize>, to: Vector2<usize>) {
+        let cells = self.toolbox.shape_cells(from, to, self.world.width(), self.world.height());
+        let color = self.world.materials().get(self.toolbox.material()).color;
+        self.canvas.set_draw_color(Color::RGBA(color.r, color.g, color.b, 128));
+        for cell in cells.iter() {
+            let rect = self.cells_rect(cell.x, cell.y, 1, 1);
+            self.canvas.fill_rect(rect).unwrap();
+        }
+    }
+
+    // Rooms tinted by how hot they are, their boundaries, and the cells
+    // `process_frame` just returned flashing, with the counts in a corner
+    pub fn draw_debug_overlay(&mut self, updated_cells: &Vec<Vector2<usize>>) {
+        let hotness = self.world.rooms_hotness().clone();
+        let room_size = self.world.room_size();
+        let cooldown = self.world.config().hotness_cooldown;
+        let world = self.cells_rect(0, 0, self.world.width(), self.world.height());
+
+        let mut hot_rooms = 0;
+        for room_y in 0..hotness.height() {
+            for room_x in 0..hotness.width() {
+                let value = *hotness.get(room_x, room_y);
+                if (value == 0) {
+                    continue;
+                }
+                hot_rooms += 1;
+                let alpha = 30 + (90 * value.min(cooldown) / cooldown) as u8;
+                self.canvas.set_draw_color(Color::RGBA(255, 80, 0, alpha));
+                let rect = self.cells_rect(room_x * room_size.x, room_y * room_size.y, room_size.x, room_size.y);
+                self.canvas.fill_rect(rect.intersection(world).unwrap_or(rect)).unwrap();
+            }
+        }
+
+        self.canvas.set_draw_color(Color::RGBA(0, 0, 0, 60));
+        for room_x in 1..hotness.width() {
+            let x = self.cells_rect(room_x * room_size.x, 0, 1, 1).x();
+            self.canvas.draw_line(Point::new(x, world.top()), Point::new(x, world.bottom())).unwrap();
+        }
+        for room_y in 1..hotness.height() {
+            let y = self.cells_rect(0, room_y * room_size.y, 1, 1).y();
+            self.canvas.draw_line(Point::new(world.left(), y), Point::new(world.right(), y)).unwrap();
+        }
+
+        let flashes : Vec<Rect> = updated_cells.iter().map(|cell| self.cells_rect(cell.x, cell.y, 1, 1)).collect();
+        self.canvas.set_draw_color(Color::RGBA(255, 0, 255, 160));
+        self.canvas.fill_rects(&flashes).unwrap();
+
+        self.draw_text(
+            format!("Hot rooms: {}/{}", hot_rooms, hotness.width() * hotness.height()).as_str(),
+            Vector2{x:550, y:30},
+        );
+        self.draw_text(format!("Updated: {}", updated_cells.len()).as_str(), Vector2{x:550, y:60});
+    }
+
+    // A colour square, outlined when selected
+    fn draw_swatch(&mut self, color: Color, pos: Vector2<usize>, selected: bool) {
+        let rect = Rect::new(pos.x as i32, pos.y as i32, 22, 22);
+        self.canvas.set_draw_color(color);
+        self.canvas.fill_rect(rect).unwrap();
+        self.canvas.set_draw_color(Color::RGB(0, 0, 0));
+        self.canvas.draw_rect(rect).unwrap();
+        if (selected) {
+            self.canvas.draw_rect(Rect::new(pos.x as i32 - 2, pos.y as i32 - 2, 26, 26)).unwrap();
+        }
+    }
+
     pub fn draw_text(&mut self, str:&str, pos:Vector2<usize>) {
         let surface = self.font
             .render(&str)
@@ -116,6 +315,14 @@ impl<'a> Context<'a> {
         );
     }
 
+    // The whole window as it was last drawn, HUD included
+    pub fn save_screenshot(&self, path: &str) -> Result<(), String> {
+        let (width, height) = self.canvas.output_size()?;
+        let mut pixels = self.canvas.read_pixels(None, PixelFormatEnum::RGBA32)?;
+        let surface = Surface::from_data(&mut pixels, width, height, width * 4, PixelFormatEnum::RGBA32)?;
+        surface.save(path).map_err(|e| format!("{}: {}", path, e))
+    }
+
     // Receives an iterator of vector2 to update
     pub fn draw_cells(&mut self, cells: &Vec<Vector2<usize>>) {
         let width = self.world.width().clone();
@@ -126,29 +333,16 @@ impl<'a> Context<'a> {
             for cell in cells.iter() {
                 let x = cell.x;
                 let y = cell.y;
-                match self.world.get(x, y) {
-                    CellType::Sand(data) => {
-                        let i = (x + y*width) as usize * 4;
-                        buffer[i] = data.color.r;
-                        buffer[i+1] = data.color.g;
-                        buffer[i+2] = data.color.b;
-                        buffer[i+3] = data.color.a;
-                    },
-                    _ => {
-                        let i = (x + y*width) as usize * 4;
-                        buffer[i] = 255;
-                        buffer[i+1] = 255;
-                        buffer[i+2] = 255;
-                        buffer[i+3] = 0;
-                    }
-                }
+                let color = self.world.get(x, y).color();
+                let i = (x + y*width) as usize * 4;
+                buffer[i] = color.r;
+                buffer[i+1] = color.g;
+                buffer[i+2] = color.b;
+                buffer[i+3] = color.a;
             }
         }).unwrap();
-        self.canvas.copy(
-            &self.cells_texture,
-            None,
-            None //Rect::new(0, 0, self.world.width() as u32, self.world.height() as u32),
-        );
+        let world = self.cells_rect(0, 0, width, height);
+        self.canvas.copy(&self.cells_texture, None, world);
     }
 
 }
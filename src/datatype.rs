@@ -1,4 +1,5 @@
 use std::{cmp::{max, min}, fmt::Display, ops::{Mul, MulAssign}, process::Output};
+use std::marker::PhantomData;
 
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
@@ -93,3 +94,128 @@ where T: Clone
     }
 
 }
+
+
+// A `GridMap` several threads read and write at once, each in its own cells.
+// Cells are copied in and out, no reference to one ever escapes.
+pub struct SharedGrid<'a, T> {
+    cells: *mut T,
+    width: usize,
+    height: usize,
+    grid: PhantomData<&'a mut GridMap<T>>,
+}
+
+// Sound as long as the promise made to `SharedGrid::new` is kept
+unsafe impl<T: Send + Sync> Sync for SharedGrid<'_, T> {}
+
+impl<'a, T> SharedGrid<'a, T>
+where T: Clone
+{
+    // SAFETY: while the grid is shared, no cell may be accessed by two threads
+    pub unsafe fn new(grid: &'a mut GridMap<T>) -> Self {
+        SharedGrid {
+            cells: grid.grid.as_mut_ptr(),
+            width: grid.width,
+            height: grid.height,
+            grid: PhantomData,
+        }
+    }
+
+    fn index(&self, x:usize, y:usize) -> usize {
+        assert!(x < self.width && y < self.height, "cell ({}, {}) is outside the grid", x, y);
+        x + y*self.width
+    }
+
+    pub fn get(&self, x:usize, y:usize) -> T {
+        let i = self.index(x, y);
+        // SAFETY: in bounds, and no other thread is on this cell (see `new`)
+        unsafe { (*self.cells.add(i)).clone() }
+    }
+
+    pub fn set(&self, x:usize, y:usize, new_val:T) {
+        let i = self.index(x, y);
+        // SAFETY: as in `get`
+        unsafe { *self.cells.add(i) = new_val; }
+    }
+
+    pub fn swap(&self, x1:usize, y1:usize, x2:usize, y2:usize) {
+        let i1 = self.index(x1, y1);
+        let i2 = self.index(x2, y2);
+        // SAFETY: as in `get`, `ptr::swap` is fine with both being the same cell
+        unsafe { std::ptr::swap(self.cells.add(i1), self.cells.add(i2)); }
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+    pub fn height(&self) -> usize {
+        self.height
+    }
+}
+
+
+// Cells of the segment between two cells, both ends included (Bresenham)
+pub fn line(from: Vector2<usize>, to: Vector2<usize>) -> Vec<Vector2<usize>> {
+    let (mut x, mut y) = (from.x as i64, from.y as i64);
+    let (x1, y1) = (to.x as i64, to.y as i64);
+    let dx = (x1 - x).abs();
+    let dy = -(y1 - y).abs();
+    let sx = if x < x1 { 1 } else { -1 };
+    let sy = if y < y1 { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    let mut ret = vec![];
+    loop {
+        ret.push(Vector2 { x: x as usize, y: y as usize });
+        if x == x1 && y == y1 {
+            break;
+        }
+        let e2 = 2 * error;
+        if e2 >= dy {
+            error += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            error += dx;
+            y += sy;
+        }
+    }
+    return ret;
+}
+
+// Cells of the rectangle with these opposite corners, just the border unless filled
+pub fn rectangle(a: Vector2<usize>, b: Vector2<usize>, filled: bool) -> Vec<Vector2<usize>> {
+    let (left, right) = (min(a.x, b.x), max(a.x, b.x));
+    let (top, bottom) = (min(a.y, b.y), max(a.y, b.y));
+    let mut ret = vec![];
+    for y in top..=bottom {
+        for x in left..=right {
+            if filled || x == left || x == right || y == top || y == bottom {
+                ret.push(Vector2 { x, y });
+            }
+        }
+    }
+    return ret;
+}
+
+// Cells of the circle around `center`, just the ring unless filled. Can go
+// past zero, hence the signed coordinates.
+pub fn circle(center: Vector2<usize>, radius: usize, filled: bool) -> Vec<Vector2<i64>> {
+    let r = radius as i64;
+    // r² + r rounds the disc nicely on small radii
+    let inside = |dx: i64, dy: i64| dx*dx + dy*dy <= r*r + r;
+    let mut ret = vec![];
+    for dy in -r..=r {
+        for dx in -r..=r {
+            if !inside(dx, dy) {
+                continue;
+            }
+            // Any of the 8 neighbours outside, so the ring has no diagonal gaps to leak through
+            let border = (-1..=1).any(|ny| (-1..=1).any(|nx| !inside(dx+nx, dy+ny)));
+            if filled || border {
+                ret.push(Vector2 { x: center.x as i64 + dx, y: center.y as i64 + dy });
+            }
+        }
+    }
+    return ret;
+}
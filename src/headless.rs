@@ -0,0 +1,106 @@
+// This is headless module
+use std::fs;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::material::*;
+use crate::scene;
+use crate::session::*;
+use crate::toolbox::ToolBox;
+use crate::history::*;
+use crate::world_grid::*;
+
+
+// Runs the simulation without opening any window, for batch runs and CI:
+//
+//     sandbox --headless --frames 600 [--seed 42] [--scene start.txt|start.png]
+//             [--out final.txt] [--stats stats.txt] [--size 400x300]
+//             [--png final.png] [--replay session.log] [--history-mb 64]
+//             [--threads 4] [--rooms 16x16 | --room-size 32x32] [--cooldown 12]
+//             [--gravity 0.15] [--max-fall-speed 8] [--slide 2-4]
+//
+// A replay starts from the recorded world and lasts as long as the recording
+// unless --frames says otherwise.
+pub fn run(args: &[String], seed: u64, materials: Arc<MaterialRegistry>, replay: Option<Session>) -> Result<(), String> {
+    let frames = match (crate::arg_value(args, "--frames"), &replay) {
+        (Some(value), _) => value.parse::<usize>().map_err(|_| "--frames needs a number".to_string())?,
+        (None, Some(session)) => session.frames(),
+        (None, None) => 600,
+    };
+    let out_path = crate::arg_value(args, "--out").unwrap_or("final.txt");
+    let stats_path = crate::arg_value(args, "--stats").unwrap_or("stats.txt");
+
+    let config = crate::world_config(args)?;
+    let mut world = match (&replay, crate::arg_value(args, "--scene")) {
+        (Some(session), _) => session.start_world(materials)?,
+        (None, Some(path)) => scene::load(path, seed, config, materials)?,
+        (None, None) => {
+            let (width, height) = crate::size_arg(args)?.unwrap_or((400, 300));
+            World::with_seed(width, height, seed, config, materials)
+        },
+    };
+
+    if let Some(value) = crate::arg_value(args, "--threads") {
+        world.set_threads(value.parse::<usize>().map_err(|_| "--threads needs a number".to_string())?);
+    }
+
+    let start = Instant::now();
+    let mut updated_total = 0;
+    let mut updated_max = 0;
+    let mut last_active_frame = None;
+    let mut toolbox = ToolBox::new(world.materials());
+    let mut history = History::new(crate::history_cap(args)?);
+    for frame in 0..frames {
+        if let Some(session) = &replay {
+            for action in session.actions_at(frame) {
+                action.perform(&mut world, &mut toolbox, &mut history);
+            }
+        }
+        let updated = world.process_frame().len();
+        updated_total += updated;
+        updated_max = updated_max.max(updated);
+        if updated > 0 {
+            last_active_frame = Some(frame);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let mut stats = String::new();
+    stats += &format!("seed: {}\n", world.seed());
+    stats += &format!("size: {}x{}\n", world.width(), world.height());
+    stats += &format!("frames: {}\n", frames);
+    stats += &format!("threads: {}\n", world.threads());
+    stats += &match world.config().rooms {
+        RoomLayout::Count(count) => format!("rooms: {}x{}\n", count.x, count.y),
+        RoomLayout::Size(size) => format!("room_size: {}x{}\n", size.x, size.y),
+    };
+    stats += &format!("hotness_cooldown: {}\n", world.config().hotness_cooldown);
+    stats += &format!("gravity: {}\n", world.config().gravity);
+    stats += &format!("max_fall_speed: {}\n", world.config().max_fall_speed);
+    stats += &format!("slide_range: {}-{}\n", world.config().slide_range.start(), world.config().slide_range.end());
+    stats += &format!("elapsed_ms: {:.3}\n", elapsed.as_secs_f64() * 1000.0);
+    stats += &format!("ms_per_frame: {:.3}\n", elapsed.as_secs_f64() * 1000.0 / frames.max(1) as f64);
+    stats += &format!("updated_cells_total: {}\n", updated_total);
+    stats += &format!("updated_cells_max: {}\n", updated_max);
+    stats += &format!("last_active_frame: {}\n", last_active_frame.map_or("none".to_string(), |f| f.to_string()));
+
+    let mut counts = vec![0usize; world.materials().len()];
+    for x in 0..world.width() {
+        for y in 0..world.height() {
+            counts[world.get(x, y).material() as usize] += 1;
+        }
+    }
+    for material in world.materials().iter() {
+        if counts[material.id as usize] > 0 {
+            stats += &format!("count.{}: {}\n", material.name, counts[material.id as usize]);
+        }
+    }
+
+    print!("{}", stats);
+    fs::write(stats_path, stats).map_err(|e| format!("{}: {}", stats_path, e))?;
+    scene::save_text(&world, out_path)?;
+    if let Some(png_path) = crate::arg_value(args, "--png") {
+        scene::save_image(&world, png_path)?;
+    }
+    Ok(())
+}
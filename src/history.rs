@@ -0,0 +1,161 @@
+// This is history module
+use std::collections::HashMap;
+use std::collections::VecDeque;
+
+use crate::datatype::Vector2;
+use crate::world_grid::*;
+
+
+// Memory the history may use before forgetting its oldest strokes
+pub const DEFAULT_MEMORY_CAP: usize = 64 * 1024 * 1024;
+
+// Everything a stroke (mouse down to mouse up) did to the world
+struct Stroke {
+    // One per cell, before the first change and after the last one
+    changes: Vec<CellChange>,
+    // Whole world just before the stroke, see `History::revert`
+    snapshot: Vec<u8>,
+}
+
+impl Stroke {
+    fn memory(&self) -> usize {
+        self.changes.len() * std::mem::size_of::<CellChange>() + self.snapshot.len()
+    }
+}
+
+
+// Undo/redo of paint strokes. Undoing puts back only the cells a stroke
+// painted, the rest of the world keeps simulating; reverting goes back to the
+// whole world as it was before the stroke.
+pub struct History {
+    // Strokes before `position` are done, the ones after were undone
+    strokes: VecDeque<Stroke>,
+    position: usize,
+    memory_cap: usize,
+    // Snapshot of the stroke in progress, if any
+    current: Option<Vec<u8>>,
+}
+
+impl History {
+
+    pub fn new(memory_cap: usize) -> History {
+        History {
+            strokes: VecDeque::new(),
+            position: 0,
+            memory_cap: memory_cap,
+            current: None,
+        }
+    }
+
+    pub fn memory_cap(&self) -> usize {
+        self.memory_cap
+    }
+
+    pub fn set_memory_cap(&mut self, memory_cap: usize) {
+        self.memory_cap = memory_cap;
+        self.forget();
+    }
+
+    // Memory used by the strokes kept
+    pub fn memory(&self) -> usize {
+        self.strokes.iter().map(|stroke| stroke.memory()).sum()
+    }
+
+    pub fn begin_stroke(&mut self, world: &mut World) {
+        let mut snapshot = vec![];
+        world.save(&mut snapshot).unwrap();
+        self.current = Some(snapshot);
+        world.start_journal();
+    }
+
+    pub fn end_stroke(&mut self, world: &mut World) {
+        let Some(snapshot) = self.current.take() else {
+            return;
+        };
+
+        // Merging repeated changes of a cell, keeping the order cells were first touched
+        let mut changes = Vec::<CellChange>::new();
+        let mut index = HashMap::<Vector2<usize>, usize>::new();
+        for change in world.take_journal() {
+            match index.get(&change.position) {
+                Some(&i) => {
+                    changes[i].after = change.after;
+                    changes[i].after_temperature = change.after_temperature;
+                },
+                None => {
+                    index.insert(change.position, changes.len());
+                    changes.push(change);
+                },
+            }
+        }
+        if changes.is_empty() {
+            return;
+        }
+
+        // A new stroke drops whatever could have been redone
+        self.strokes.truncate(self.position);
+        self.strokes.push_back(Stroke { changes, snapshot });
+        self.position += 1;
+        self.forget();
+    }
+
+    // Whether a stroke is being painted, undoing waits until it's over
+    fn in_stroke(&self) -> bool {
+        self.current.is_some()
+    }
+
+    // Returns false when there's nothing left to undo, or a stroke is in progress
+    pub fn undo(&mut self, world: &mut World) -> bool {
+        if self.in_stroke() || self.position == 0 {
+            return false;
+        }
+        self.position -= 1;
+        for change in self.strokes[self.position].changes.iter().rev() {
+            world.set(change.position.x, change.position.y, change.before.clone());
+            world.set_temperature(change.position.x, change.position.y, change.before_temperature);
+        }
+        return true;
+    }
+
+    // Returns false when there's nothing left to redo, or a stroke is in progress
+    pub fn redo(&mut self, world: &mut World) -> bool {
+        if self.in_stroke() || self.position == self.strokes.len() {
+            return false;
+        }
+        for change in self.strokes[self.position].changes.iter() {
+            world.set(change.position.x, change.position.y, change.after.clone());
+            world.set_temperature(change.position.x, change.position.y, change.after_temperature);
+        }
+        self.position += 1;
+        return true;
+    }
+
+    // The whole simulation back to just before the last stroke, which can then
+    // be redone on top of it. Returns false when there's no stroke to go back to,
+    // or one is in progress.
+    pub fn revert(&mut self, world: &mut World) -> bool {
+        if self.in_stroke() || self.position == 0 {
+            return false;
+        }
+        self.position -= 1;
+        let snapshot = &self.strokes[self.position].snapshot;
+        let mut reverted = World::load(snapshot.as_slice(), world.materials().clone()).unwrap();
+        reverted.set_threads(world.threads());
+        *world = reverted;
+        return true;
+    }
+
+    // Oldest done strokes go first, then the furthest undone ones. The last one is always kept.
+    fn forget(&mut self) {
+        let mut memory = self.memory();
+        while memory > self.memory_cap && self.strokes.len() > 1 {
+            let stroke = if self.position > 0 {
+                self.position -= 1;
+                self.strokes.pop_front().unwrap()
+            } else {
+                self.strokes.pop_back().unwrap()
+            };
+            memory -= stroke.memory();
+        }
+    }
+}
@@ -6,7 +6,8 @@ use rand::seq::SliceRandom;
 use sdl2::libc::{abs, rand};
 use sdl2::pixels::{Color, PixelFormat, PixelFormatEnum};
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Mod};
+use sdl2::mouse::MouseButton;
 use sdl2::rect::{Point, Rect};
 use sdl2::rwops::RWops;
 use sdl2::sys::{random, random_data};
@@ -23,21 +24,78 @@ use sdl2::Sdl;
 use crate::world_grid::*;
 use crate::toolbox::*;
 use crate::context::*;
+use crate::material::*;
+use crate::session::*;
+use crate::history::*;
+use std::sync::Arc;
 
 mod world_grid;
+mod material;
+mod rng;
 mod toolbox;
 mod datatype;
 mod context;
+mod scene;
+mod palette;
+mod session;
+mod history;
+mod headless;
+
+// F5 saves the world here, F9 brings it back
+const QUICKSAVE_PATH: &str = "quicksave.sbx";
 
 pub fn main() {
     let bg_color = Color::RGBA(255, 255, 255, 255);
     let point_size : usize = 2;
     
 
-    let mut world = World::new(800/point_size, 600/point_size);
+    let materials = Arc::new(MaterialRegistry::load("assets/materials.txt").unwrap());
+    // `--seed <n>` replays the exact same simulation for the same input
+    let args : Vec<String> = std::env::args().collect();
+    // `--replay <log>` plays a recorded session back, from its own seed
+    let replay = arg_value(&args, "--replay").map(|path| Session::load(path, &materials).unwrap());
+    let seed = match (&replay, arg_value(&args, "--seed")) {
+        (Some(session), _) => session.seed(),
+        (None, Some(value)) => value.parse::<u64>().expect("--seed needs a number"),
+        (None, None) => rand::thread_rng().gen(),
+    };
+    println!("seed: {}", seed);
+
+    if args.iter().any(|arg| arg == "--headless") {
+        if let Err(e) = headless::run(&args, seed, materials, replay) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // `--scene <file>` starts from a text layout or an image instead of an empty world,
+    // `--size <width>x<height>` sets the size of an empty one, the camera fits it in the window
+    let scene_path = arg_value(&args, "--scene");
+    let config = world_config(&args).unwrap();
+    let size = size_arg(&args).unwrap().unwrap_or((800/point_size, 600/point_size));
+    let mut world = match (&replay, scene_path) {
+        (Some(session), _) => session.start_world(materials).unwrap(),
+        (None, Some(path)) => scene::load(path, seed, config, materials).unwrap(),
+        (None, None) => World::with_seed(size.0, size.1, seed, config, materials),
+    };
+    // `--threads <n>` for the simulation, all cores by default
+    if let Some(value) = arg_value(&args, "--threads") {
+        world.set_threads(value.parse::<usize>().expect("--threads needs a number"));
+    }
+    // `--record <log>` writes every action of this session there on exit
+    let record_path = arg_value(&args, "--record");
+    let mut recording = record_path.map(|_| Session::new(&world, scene_path));
+    let mut frame : usize = 0;
+    let mut history = History::new(history_cap(&args).unwrap());
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let mut painting = false;
+    // Button held down over the world, left paints and right erases
+    let mut painting : Option<MouseButton> = None;
+    // Where the left button went down, shapes are drawn from there.
+    // None when it went down off the world
+    let mut drag_start : Option<Vector2<usize>> = None;
+    let mut screenshot = false;
     let mut counter = 0;
     let window = video_subsystem
         .window("sandbox", 800, 600)
@@ -46,60 +104,232 @@ pub fn main() {
         .unwrap();
     let mut canvas = window.into_canvas().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    let mut last_mouse_pos = Vector2::<usize>{x:0, y:0};
+    // In window pixels, see `Context::screen_to_cell` for the cell under it
+    let mut last_mouse_pos = Vector2::<i32>{x:0, y:0};
     let mut last_frame_times = VecDeque::<usize>::new();
     let mut ttf = sdl2::ttf::init().unwrap();
     let mut texture_creator = canvas.texture_creator();
 
     canvas.set_blend_mode(sdl2::render::BlendMode::Blend);
-    let mut context = Context::new(&mut canvas, world, point_size, &ttf, &texture_creator);
+    let mut context = Context::new(&mut canvas, world, &ttf, &texture_creator);
+    // The starting tool is part of the recording too
+    if let Some(session) = &mut recording {
+        session.record(0, Action::SelectTool(context.toolbox.tool()));
+        session.record(0, Action::SelectMaterial(context.toolbox.material()));
+        session.record(0, Action::SelectColor(context.toolbox.color_mode(), context.toolbox.current_color_index()));
+        session.record(0, Action::SetBrush(context.toolbox.brush_shape(), context.toolbox.mouse_box().x, context.toolbox.points_per_paint()));
+    }
 
     'running: loop {
         let loop_start = std::time::Instant::now();
         context.canvas.set_draw_color(bg_color);
         context.canvas.clear();
 
+        let mut actions = Vec::<Action>::new();
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                     break 'running
                 },
+                Event::KeyDown { keycode: Some(Keycode::Z), keymod, .. } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    actions.push(Action::Undo);
+                },
+                Event::KeyDown { keycode: Some(Keycode::Y), keymod, .. } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    actions.push(Action::Redo);
+                },
+                Event::KeyDown { keycode: Some(Keycode::R), keymod, .. } if keymod.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) => {
+                    actions.push(Action::Revert);
+                },
+                // Shortcuts to materials the definitions file may not have, they do nothing then
+                Event::KeyDown { keycode: Some(Keycode::S), .. } => {
+                    if let Some(sand) = context.world.materials().id("sand") {
+                        actions.push(Action::SelectMaterial(sand));
+                        actions.push(Action::SelectTool(Tool::Paint));
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::B), .. } => {
+                    if let Some(block) = context.world.materials().id("block") {
+                        actions.push(Action::SelectMaterial(block));
+                        actions.push(Action::SelectTool(Tool::Paint));
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::E), .. } => {
+                    actions.push(Action::SelectTool(Tool::Erase));
+                },
+                Event::KeyDown { keycode: Some(Keycode::X), .. } => {
+                    actions.push(Action::SelectTool(Tool::EraseMaterial));
+                },
+                // Shapes are hollow, or filled with shift
+                Event::KeyDown { keycode: Some(Keycode::L), .. } => {
+                    actions.push(Action::SelectTool(Tool::Line));
+                },
+                Event::KeyDown { keycode: Some(Keycode::R), keymod, .. } => {
+                    let filled = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    actions.push(Action::SelectTool(if (filled) { Tool::FilledRectangle } else { Tool::Rectangle }));
+                },
+                Event::KeyDown { keycode: Some(Keycode::O), keymod, .. } => {
+                    let filled = keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD);
+                    actions.push(Action::SelectTool(if (filled) { Tool::FilledCircle } else { Tool::Circle }));
+                },
+                Event::KeyDown { keycode: Some(Keycode::F), .. } => {
+                    actions.push(Action::SelectTool(Tool::Bucket));
+                },
+                Event::KeyDown { keycode: Some(Keycode::Q), .. } => {
+                    let toolbox = &context.toolbox;
+                    actions.push(Action::SetBrush(toolbox.brush_shape().next(), toolbox.mouse_box().x, toolbox.points_per_paint()));
+                },
+                // The wheel zooms around the mouse, with ctrl it resizes the brush
+                // and with shift it changes how much is sprayed
+                Event::MouseWheel { y, .. } => {
+                    let toolbox = &context.toolbox;
+                    let mod_state = sdl_context.keyboard().mod_state();
+                    if mod_state.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        let points = toolbox.points_per_paint().saturating_add_signed(5 * y as isize);
+                        actions.push(Action::SetBrush(toolbox.brush_shape(), toolbox.mouse_box().x, points));
+                    } else if mod_state.intersects(Mod::LCTRLMOD | Mod::RCTRLMOD) {
+                        let size = toolbox.mouse_box().x.saturating_add_signed(2 * y as isize);
+                        actions.push(Action::SetBrush(toolbox.brush_shape(), size, toolbox.points_per_paint()));
+                    } else {
+                        context.zoom_at(last_mouse_pos, 1.25f32.powi(y));
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::Home), .. } => {
+                    context.reset_camera();
+                },
+                Event::KeyDown { keycode: Some(Keycode::C), .. } => {
+                    let (mode, index) = context.toolbox.next_color();
+                    actions.push(Action::SelectColor(mode, index));
+                },
+                Event::KeyDown { keycode: Some(keycode), .. } if hotbar_slot(keycode).is_some() => {
+                    if let Some(&material) = context.toolbox.hotbar().get(hotbar_slot(keycode).unwrap()) {
+                        actions.push(Action::SelectMaterial(material));
+                        actions.push(Action::SelectTool(Tool::Paint));
+                    }
+                },
+                // Debug overlay, only for this window so it isn't recorded
+                Event::KeyDown { keycode: Some(Keycode::F3), .. } => {
+                    context.debug_overlay = !context.debug_overlay;
+                },
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                    let saved = std::fs::File::create(QUICKSAVE_PATH)
+                        .map_err(|e| e.to_string())
+                        .and_then(|mut file| context.world.save(&mut file));
+                    match saved {
+                        Ok(()) => println!("saved to {}", QUICKSAVE_PATH),
+                        Err(e) => eprintln!("{}: {}", QUICKSAVE_PATH, e),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } => {
+                    let loaded = std::fs::File::open(QUICKSAVE_PATH)
+                        .map_err(|e| e.to_string())
+                        .and_then(|file| World::load(std::io::BufReader::new(file), context.world.materials().clone()));
+                    match loaded {
+                        // A recording or replay has to start from its own world
+                        Ok(_) if recording.is_some() || replay.is_some() => {
+                            eprintln!("can't load a world while recording or replaying");
+                        },
+                        // The cell texture is sized for the current world
+                        Ok(world) if world.width() != context.world.width() || world.height() != context.world.height() => {
+                            eprintln!("{}: saved world is {}x{}, this one is {}x{}", QUICKSAVE_PATH,
+                                world.width(), world.height(), context.world.width(), context.world.height());
+                        },
+                        Ok(mut world) => {
+                            println!("loaded {} (seed {})", QUICKSAVE_PATH, world.seed());
+                            world.set_threads(context.world.threads());
+                            context.world = world;
+                        },
+                        Err(e) => eprintln!("{}: {}", QUICKSAVE_PATH, e),
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F6), .. } => {
+                    let path = format!("world-{}.png", unix_time());
+                    match scene::save_image(&context.world, &path) {
+                        Ok(()) => println!("exported {}", path),
+                        Err(e) => eprintln!("{}", e),
+                    }
+                },
+                // Taken once the frame is fully drawn
+                Event::KeyDown { keycode: Some(Keycode::F12), .. } => {
+                    screenshot = true;
+                },
                 Event::MouseMotion {
                     timestamp, window_id, which,
                     mousestate, x, y, xrel, yrel,
                 } => {
-                    last_mouse_pos = Vector2{x: x as usize, y: y as usize};
+                    last_mouse_pos = Vector2{x: x, y: y};
+                    // Dragging with the middle button moves the camera
+                    if mousestate.middle() {
+                        context.pan(xrel, yrel);
+                    }
                 },
-                Event::MouseButtonDown { timestamp, window_id, which, mouse_btn, clicks, x, y } => {
-                    painting = true
+                Event::MouseButtonDown { timestamp, window_id, which, mouse_btn, clicks, x, y }
+                    if painting.is_none() && (mouse_btn == MouseButton::Left || mouse_btn == MouseButton::Right) => {
+                    painting = Some(mouse_btn);
+                    actions.push(Action::BeginStroke);
+                    drag_start = context.screen_to_cell(Vector2{x: x, y: y});
+                    // The bucket fills once per click
+                    if (mouse_btn == MouseButton::Left && context.toolbox.tool() == Tool::Bucket) {
+                        if let Some(start) = drag_start {
+                            actions.push(Action::Apply(start));
+                        }
+                    }
                 },
-                Event::MouseButtonUp { timestamp, window_id, which, mouse_btn, clicks, x, y } => {
-                    painting = false
+                Event::MouseButtonUp { timestamp, window_id, which, mouse_btn, clicks, x, y } if painting == Some(mouse_btn) => {
+                    painting = None;
+                    if (mouse_btn == MouseButton::Left && context.toolbox.tool().is_shape()) {
+                        // a shape needs both of its ends on the world
+                        if let (Some(start), Some(end)) = (drag_start, context.screen_to_cell(Vector2{x: x, y: y})) {
+                            actions.push(Action::Draw(start, end));
+                        }
+                    }
+                    actions.push(Action::EndStroke);
                 },
                 _ => {}
             }
         }
         
-        // Drawing a rectangle on mouse
-        context.canvas.set_draw_color(Color::RGB(0, 0, 0));
-        context.canvas.draw_rect(
-            Rect::new(
-                last_mouse_pos.clone().x as i32 - (point_size * context.toolbox.mouse_box().x) as i32 / 2 ,
-                last_mouse_pos.clone().y as i32 - (point_size * context.toolbox.mouse_box().y) as i32 / 2 ,
-                (context.toolbox.mouse_box().x * point_size) as u32,
-                (context.toolbox.mouse_box().y * point_size) as u32,
-            )
-        ).unwrap();
-
-        // Painting
-        if (painting) {
-            mouse_tick(&mut context, last_mouse_pos);
+        let mouse_cell = context.screen_to_cell(last_mouse_pos);
+        let tool = context.toolbox.tool();
+
+        // Outline of what the brush covers under the mouse, or the shape being dragged
+        if (painting == Some(MouseButton::Left) && tool.is_shape()) {
+            if let (Some(start), Some(end)) = (drag_start, mouse_cell) {
+                context.draw_shape_preview(start, end);
+            }
+        } else if let Some(cell) = mouse_cell {
+            context.draw_brush(cell);
+        }
+
+        // Painting, nothing to paint off the world
+        match (painting, mouse_cell) {
+            (Some(MouseButton::Left), _) if tool.is_shape() || tool == Tool::Bucket => {},
+            (Some(MouseButton::Left), Some(cell)) => actions.push(Action::Apply(cell)),
+            (Some(MouseButton::Right), Some(cell)) => actions.push(Action::Erase(cell)),
+            _ => {},
+        }
+
+        // A replay ignores the user and does what was recorded instead
+        if let Some(session) = &replay {
+            actions = session.actions_at(frame).cloned().collect();
+            if (frame == session.frames()) {
+                println!("replay finished after {} frames", frame);
+            }
+        }
+        for action in actions {
+            action.perform(&mut context.world, &mut context.toolbox, &mut history);
+            if let Some(session) = &mut recording {
+                session.record(frame, action);
+            }
         }
 
         // Processing the world
         let updated_cells = context.world.process_frame();
+        frame += 1;
         context.draw_cells(&updated_cells);
+        if (context.debug_overlay) {
+            context.draw_debug_overlay(&updated_cells);
+        }
         
         context.draw_toolbox();
         
@@ -118,45 +348,117 @@ pub fn main() {
             format!("FPS: {}", (1000000.0 * last_frame_times.len() as f32 / (time_sum as f32)) as i32 ).as_str(),
             Vector2{x:550, y:0},
         );
+        if (screenshot) {
+            screenshot = false;
+            let path = format!("screenshot-{}.png", unix_time());
+            match context.save_screenshot(&path) {
+                Ok(()) => println!("saved {}", path),
+                Err(e) => eprintln!("{}", e),
+            }
+        }
         context.canvas.present();
         if (delta_t > elapsed) {
             std::thread::sleep( delta_t - elapsed );
         }
     }
+
+    if let (Some(session), Some(path)) = (&mut recording, record_path) {
+        session.set_frames(frame);
+        match session.save(path, context.world.materials()) {
+            Ok(()) => println!("recorded {} frames to {}", frame, path),
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+}
+
+// Seconds since the epoch, keeps exported file names apart
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// Number keys pick from the toolbox hotbar, 1 first and 0 last
+fn hotbar_slot(keycode: Keycode) -> Option<usize> {
+    match keycode {
+        Keycode::Num1 => Some(0),
+        Keycode::Num2 => Some(1),
+        Keycode::Num3 => Some(2),
+        Keycode::Num4 => Some(3),
+        Keycode::Num5 => Some(4),
+        Keycode::Num6 => Some(5),
+        Keycode::Num7 => Some(6),
+        Keycode::Num8 => Some(7),
+        Keycode::Num9 => Some(8),
+        Keycode::Num0 => Some(9),
+        _ => None,
+    }
 }
 
-fn mouse_tick(context: &mut Context, mouse_pos:Vector2<usize>) {
-    let width = context.world.width().clone();
-    let height = context.world.height().clone();
-    let mut world = &mut context.world;
-    let toolbox = &context.toolbox;
-    let canvas = &context.canvas;
+// `--history-mb <n>` caps the memory kept for undo
+pub fn history_cap(args: &[String]) -> Result<usize, String> {
+    match arg_value(args, "--history-mb") {
+        Some(value) => value.parse::<usize>()
+            .map(|mb| mb * 1024 * 1024)
+            .map_err(|_| "--history-mb needs a number".to_string()),
+        None => Ok(DEFAULT_MEMORY_CAP),
+    }
+}
 
-    let center_grid = Vector2::<usize> {
-        x: mouse_pos.x / context.point_size,
-        y: mouse_pos.y / context.point_size,
+// Simulation knobs, the defaults unless given:
+//
+//     --rooms 16x16 | --room-size 32x32  --cooldown 12  --gravity 0.15
+//     --max-fall-speed 8  --slide 2-4
+//
+// A replay ignores them and uses the ones it was recorded with.
+pub fn world_config(args: &[String]) -> Result<WorldConfig, String> {
+    let mut ret = WorldConfig::default();
+    let pair = |name: &str, value: &str, separator: char| -> Result<(usize, usize), String> {
+        value.split_once(separator)
+            .and_then(|(a, b)| Some((a.parse::<usize>().ok()?, b.parse::<usize>().ok()?)))
+            .ok_or(format!("{} needs <a>{}<b>", name, separator))
     };
-    let mut center_x = max(toolbox.mouse_box().x/2, center_grid.x);
-    let mut center_y = max(toolbox.mouse_box().y/2, center_grid.y);
-    center_x = min(center_x, width - toolbox.mouse_box().x/2);
-    center_y = min(center_y, height - toolbox.mouse_box().y/2);
-    context.tick_counter += 1;
-    for i in (0..toolbox.points_per_paint()) {
-        let x = center_x - toolbox.mouse_box().x/2 + context.rng.gen_range( 0..toolbox.mouse_box().x );
-        let y = center_y - toolbox.mouse_box().y/2 + context.rng.gen_range( 0..toolbox.mouse_box().y );
-        world.set(x, y, CellType::Sand(ParticleData{
-            speed: Vector2{x:0.0, y:2.0},
-            color: Color::RGBA(
-                if (context.tick_counter % 512 >= 256) { 
-                    (context.tick_counter % 256) as u8
-                } else {
-                    255u8 - (context.tick_counter % 256) as u8
-                },
-                0,
-                0,
-                255
-            )
-        }));
+    if let Some(value) = arg_value(args, "--rooms") {
+        let (x, y) = pair("--rooms", value, 'x')?;
+        ret.rooms = RoomLayout::Count(Vector2 { x: x, y: y });
+    }
+    if let Some(value) = arg_value(args, "--room-size") {
+        let (x, y) = pair("--room-size", value, 'x')?;
+        ret.rooms = RoomLayout::Size(Vector2 { x: x, y: y });
+    }
+    if let Some(value) = arg_value(args, "--cooldown") {
+        ret.hotness_cooldown = value.parse::<usize>().map_err(|_| "--cooldown needs a number".to_string())?;
     }
+    if let Some(value) = arg_value(args, "--gravity") {
+        ret.gravity = value.parse::<f32>().map_err(|_| "--gravity needs a number".to_string())?;
+    }
+    if let Some(value) = arg_value(args, "--max-fall-speed") {
+        ret.max_fall_speed = value.parse::<f32>().map_err(|_| "--max-fall-speed needs a number".to_string())?;
+    }
+    if let Some(value) = arg_value(args, "--slide") {
+        let (from, to) = pair("--slide", value, '-')?;
+        ret.slide_range = from..=to;
+    }
+    ret.validate()?;
+    return Ok(ret);
 }
 
+// `--size <width>x<height>`, if given
+pub fn size_arg(args: &[String]) -> Result<Option<(usize, usize)>, String> {
+    let Some(value) = arg_value(args, "--size") else {
+        return Ok(None);
+    };
+    value.split_once('x')
+        .and_then(|(w, h)| Some((w.parse::<usize>().ok()?, h.parse::<usize>().ok()?)))
+        .filter(|(w, h)| *w > 0 && *h > 0)
+        .map(Some)
+        .ok_or("--size needs <width>x<height>".to_string())
+}
+
+// Value following `name` on the command line, like `--frames 600`
+pub fn arg_value<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i+1))
+        .map(|value| value.as_str())
+}
@@ -0,0 +1,401 @@
+// This is material module
+use rand::Rng;
+use sdl2::pixels::Color;
+use std::collections::HashMap;
+use std::fs;
+
+use crate::datatype::Vector2;
+use crate::world_grid::{CellType, ParticleData, AMBIENT_TEMPERATURE};
+
+
+pub type MaterialId = u8;
+
+// "empty" is always the first material of a definitions file
+pub const EMPTY: MaterialId = 0;
+
+// How a material moves around, the rest of its behaviour comes from its properties
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum State {
+    Powder,
+    Liquid,
+    Gas,
+    Solid,
+    // Doesn't move, burns its neighbours and eventually goes out
+    Fire,
+}
+
+// Something a material turns into, `chance` of it happening and empty otherwise
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Product {
+    pub material: MaterialId,
+    pub chance: f32,
+}
+
+// Becoming another material when crossing a temperature
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PhaseChange {
+    pub temperature: f32,
+    pub into: MaterialId,
+}
+
+// What the other side of a reaction has to be
+#[derive(Clone, PartialEq, Debug)]
+pub enum Reactant {
+    Material(MaterialId),
+    // Any particle but the listed ones, `*` or `!glass,block` in the file
+    AnyExcept(Vec<MaterialId>),
+}
+
+impl Reactant {
+    pub fn matches(&self, material: MaterialId) -> bool {
+        match self {
+            Reactant::Material(id) => *id == material,
+            Reactant::AnyExcept(ids) => (material != EMPTY) && !ids.contains(&material),
+        }
+    }
+}
+
+// `a` touching `b` turns them into `into_a` and `into_b`, with `chance` per tick
+#[derive(Clone, PartialEq, Debug)]
+pub struct Reaction {
+    pub a: MaterialId,
+    pub b: Reactant,
+    pub into_a: MaterialId,
+    pub into_b: MaterialId,
+    pub chance: f32,
+}
+
+#[derive(Clone, Debug)]
+pub struct Material {
+    pub id: MaterialId,
+    pub name: String,
+    pub color: Color,
+    // Max brightness offset applied to every spawned particle
+    pub color_jitter: u8,
+    // kg/m³, solids are always infinite so nothing pushes them around
+    pub density: f32,
+    pub state: State,
+    // Chance per tick that a burning neighbour sets it on fire
+    pub flammability: f32,
+    pub ignition_point: f32,
+    pub burns_into: MaterialId,
+    // Ticks the fire it turns into keeps burning
+    pub burn_time: u32,
+    pub explosion_radius: usize,
+    // Range of ticks a spawned particle lives, (0, 0) for forever
+    pub lifetime: (u32, u32),
+    pub decays_into: Option<Product>,
+    // Spawned in the empty cell above every tick, with the given chance
+    pub emits: Option<Product>,
+    // Temperature a freshly placed cell starts at
+    pub temperature: f32,
+    // Fraction of the difference to its neighbours' average it catches up each tick
+    pub conductivity: f32,
+    // Melting and boiling
+    pub heated: Option<PhaseChange>,
+    // Freezing and condensing
+    pub cooled: Option<PhaseChange>,
+}
+
+impl Material {
+
+    fn new(id: MaterialId, name: &str) -> Material {
+        Material {
+            id,
+            name: name.to_string(),
+            color: Color::RGBA(255, 255, 255, 0),
+            color_jitter: 0,
+            density: 1.0,
+            state: State::Solid,
+            flammability: 0.0,
+            ignition_point: f32::INFINITY,
+            burns_into: EMPTY,
+            burn_time: 20,
+            explosion_radius: 0,
+            lifetime: (0, 0),
+            decays_into: None,
+            emits: None,
+            temperature: AMBIENT_TEMPERATURE,
+            conductivity: 0.08,
+            heated: None,
+            cooled: None,
+        }
+    }
+
+    // A fresh cell of this material, with some colour and lifetime variation
+    pub fn spawn(&self, rng: &mut impl Rng) -> CellType {
+        if self.id == EMPTY {
+            return CellType::Empty;
+        }
+        let jitter = self.color_jitter as i32;
+        let offset = rng.gen_range(-jitter..=jitter);
+        let channel = |c: u8| (c as i32 + offset).clamp(0, 255) as u8;
+        CellType::Particle(ParticleData {
+            material: self.id,
+            speed: Vector2 {
+                x: 0.0,
+                y: if self.state == State::Gas { -1.0 } else { 0.0 },
+            },
+            color: Color::RGBA(channel(self.color.r), channel(self.color.g), channel(self.color.b), self.color.a),
+            lifetime: rng.gen_range(self.lifetime.0..=self.lifetime.1),
+        })
+    }
+}
+
+
+#[derive(Clone, Debug)]
+pub struct MaterialRegistry {
+    materials: Vec<Material>,
+    // Indexed by the `a` side of the reaction
+    reactions: Vec<Vec<Reaction>>,
+}
+
+impl MaterialRegistry {
+
+    pub fn load(path: &str) -> Result<MaterialRegistry, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        MaterialRegistry::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // Sections of `key = value` lines, one per material:
+    //
+    //     [sand]
+    //     color = 194 178 128
+    //     density = 1600
+    //     state = powder
+    //
+    // Materials may refer to each other by name in any order, ids follow the file.
+    // The special [reactions] section holds `a + b = c + d [chance]` lines instead.
+    pub fn parse(text: &str) -> Result<MaterialRegistry, String> {
+
+        // First pass only splits sections so names can be resolved to ids
+        let mut sections : Vec<(String, Vec<(usize, String, String)>)> = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                sections.push((line[1..line.len()-1].trim().to_string(), vec![]));
+            } else if let Some((key, value)) = line.split_once('=') {
+                // reactions have no keys, `a + b` goes in its place
+                match sections.last_mut() {
+                    Some((_, properties)) => properties.push((line_number, key.trim().to_string(), value.trim().to_string())),
+                    None => return Err(format!("line {}: property outside of a [material] section", line_number)),
+                }
+            } else {
+                return Err(format!("line {}: expected `[name]` or `key = value`", line_number));
+            }
+        }
+
+        let mut reaction_lines = vec![];
+        if let Some(i) = sections.iter().position(|(name, _)| name == "reactions") {
+            reaction_lines = sections.remove(i).1;
+            if sections.iter().any(|(name, _)| name == "reactions") {
+                return Err("[reactions] is defined twice".to_string());
+            }
+        }
+
+        if sections.first().map(|(name, _)| name.as_str()) != Some("empty") {
+            return Err("the first material must be [empty]".to_string());
+        }
+        if sections.len() > MaterialId::MAX as usize + 1 {
+            return Err(format!("too many materials, at most {} are supported", MaterialId::MAX as usize + 1));
+        }
+
+        let mut ids = HashMap::new();
+        for (i, (name, _)) in sections.iter().enumerate() {
+            // snapshots store names with a one byte length
+            if name.len() > u8::MAX as usize {
+                return Err(format!("material name `{}` is longer than {} bytes", name, u8::MAX));
+            }
+            if ids.insert(name.clone(), i as MaterialId).is_some() {
+                return Err(format!("material [{}] is defined twice", name));
+            }
+        }
+        let default_fire = ids.get("fire").copied().unwrap_or(EMPTY);
+
+        let mut materials = vec![];
+        for (i, (name, properties)) in sections.iter().enumerate() {
+            let mut material = Material::new(i as MaterialId, name);
+            material.burns_into = default_fire;
+            for (line_number, key, value) in properties.iter() {
+                parse_property(&mut material, key, value, &ids)
+                    .map_err(|e| format!("line {}: [{}] {}: {}", line_number, name, key, e))?;
+            }
+            if material.state == State::Solid {
+                material.density = f32::INFINITY;
+            } else if !material.density.is_finite() {
+                return Err(format!("[{}] density must be a finite number, only solids are infinitely dense", name));
+            }
+            materials.push(material);
+        }
+
+        let mut reactions = vec![vec![]; materials.len()];
+        for (line_number, key, value) in reaction_lines.iter() {
+            let reaction = parse_reaction(key, value, &ids)
+                .map_err(|e| format!("line {}: [reactions] {}", line_number, e))?;
+            reactions[reaction.a as usize].push(reaction);
+        }
+
+        Ok(MaterialRegistry { materials, reactions })
+    }
+
+    pub fn get(&self, id: MaterialId) -> &Material {
+        &self.materials[id as usize]
+    }
+
+    // Every reaction this material can start, when touching the right neighbour
+    pub fn reactions(&self, a: MaterialId) -> &[Reaction] {
+        &self.reactions[a as usize]
+    }
+
+    pub fn id(&self, name: &str) -> Option<MaterialId> {
+        self.materials.iter().find(|m| m.name == name).map(|m| m.id)
+    }
+
+    pub fn len(&self) -> usize {
+        self.materials.len()
+    }
+
+    pub fn iter(&self) -> std::slice::Iter<Material> {
+        self.materials.iter()
+    }
+}
+
+fn parse_reaction(reactants: &str, products: &str, ids: &HashMap<String, MaterialId>) -> Result<Reaction, String> {
+    let material_id = |word: &str| -> Result<MaterialId, String> {
+        ids.get(word).copied().ok_or(format!("unknown material `{}`", word))
+    };
+
+    let (a, b) = reactants.split_once('+').ok_or("expected `a + b = c + d [chance]`".to_string())?;
+    let a = material_id(a.trim())?;
+    let b = match b.trim() {
+        "*" => Reactant::AnyExcept(vec![a]),
+        b if b.starts_with('!') => {
+            let mut excluded = b[1..].split(',')
+                .map(|name| material_id(name.trim()))
+                .collect::<Result<Vec<MaterialId>, String>>()?;
+            excluded.push(a);
+            Reactant::AnyExcept(excluded)
+        },
+        b => Reactant::Material(material_id(b)?),
+    };
+
+    let (into_a, rest) = products.split_once('+').ok_or("expected `a + b = c + d [chance]`".to_string())?;
+    let words : Vec<&str> = rest.split_whitespace().collect();
+    let (into_b, chance) = match words.as_slice() {
+        [into_b] => (*into_b, 1.0),
+        [into_b, chance] => (*into_b, chance.parse::<f32>().map_err(|_| format!("`{}` is not a number", chance))?),
+        _ => return Err("expected `a + b = c + d [chance]`".to_string()),
+    };
+    if !(0.0..=1.0).contains(&chance) {
+        return Err(format!("chance `{}` is not between 0 and 1", chance));
+    }
+
+    Ok(Reaction {
+        a,
+        b,
+        into_a: material_id(into_a.trim())?,
+        into_b: material_id(into_b)?,
+        chance,
+    })
+}
+
+fn parse_property(material: &mut Material, key: &str, value: &str, ids: &HashMap<String, MaterialId>) -> Result<(), String> {
+    let words : Vec<&str> = value.split_whitespace().collect();
+    let number = |word: &str| -> Result<f32, String> {
+        match word {
+            "inf" => Ok(f32::INFINITY),
+            _ => word.parse::<f32>().map_err(|_| format!("`{}` is not a number", word)),
+        }
+    };
+    let integer = |word: &str| -> Result<u32, String> {
+        word.parse::<u32>().map_err(|_| format!("`{}` is not a positive integer", word))
+    };
+    // Chances and fractions, NaN included in what's refused
+    let fraction = |word: &str| -> Result<f32, String> {
+        let value = number(word)?;
+        if !(0.0..=1.0).contains(&value) {
+            return Err(format!("`{}` is not between 0 and 1", word));
+        }
+        Ok(value)
+    };
+    let material_id = |word: &str| -> Result<MaterialId, String> {
+        ids.get(word).copied().ok_or(format!("unknown material `{}`", word))
+    };
+    let product = |words: &[&str]| -> Result<Product, String> {
+        match words {
+            [name] => Ok(Product { material: material_id(name)?, chance: 1.0 }),
+            [name, chance] => Ok(Product { material: material_id(name)?, chance: fraction(chance)? }),
+            _ => Err("expected `<material> [chance]`".to_string()),
+        }
+    };
+    let phase_change = |words: &[&str]| -> Result<PhaseChange, String> {
+        match words {
+            [temperature, name] => Ok(PhaseChange { temperature: number(temperature)?, into: material_id(name)? }),
+            _ => Err("expected `<temperature> <material>`".to_string()),
+        }
+    };
+    let single = || -> Result<&str, String> {
+        match words.as_slice() {
+            [word] => Ok(*word),
+            _ => Err("expected a single value".to_string()),
+        }
+    };
+
+    match key {
+        "color" => {
+            let channels = words.iter()
+                .map(|w| w.parse::<u8>().map_err(|_| format!("`{}` is not a colour channel", w)))
+                .collect::<Result<Vec<u8>, String>>()?;
+            material.color = match channels.as_slice() {
+                [r, g, b] => Color::RGBA(*r, *g, *b, 255),
+                [r, g, b, a] => Color::RGBA(*r, *g, *b, *a),
+                _ => return Err("expected `r g b [a]`".to_string()),
+            };
+        },
+        "color_jitter" => material.color_jitter = integer(single()?)?.min(255) as u8,
+        "density" => material.density = number(single()?)?,
+        "state" => {
+            material.state = match single()? {
+                "powder" => State::Powder,
+                "liquid" => State::Liquid,
+                "gas" => State::Gas,
+                "solid" => State::Solid,
+                "fire" => State::Fire,
+                other => return Err(format!("unknown state `{}`", other)),
+            };
+        },
+        "flammability" => material.flammability = fraction(single()?)?,
+        "ignition_point" => material.ignition_point = number(single()?)?,
+        "burns_into" => material.burns_into = material_id(single()?)?,
+        "burn_time" => {
+            // a lifetime of 0 is forever, fire has to go out
+            material.burn_time = integer(single()?)?;
+            if material.burn_time == 0 {
+                return Err("fire has to burn at least 1 tick".to_string());
+            }
+        },
+        "explosion_radius" => material.explosion_radius = integer(single()?)? as usize,
+        "lifetime" => {
+            material.lifetime = match words.as_slice() {
+                [ticks] => (integer(ticks)?, integer(ticks)?),
+                [min, max] => (integer(min)?, integer(max)?),
+                _ => return Err("expected `<ticks>` or `<min> <max>`".to_string()),
+            };
+            if material.lifetime.0 > material.lifetime.1 {
+                return Err("minimum lifetime is bigger than the maximum".to_string());
+            }
+        },
+        "decays_into" => material.decays_into = Some(product(&words)?),
+        "emits" => material.emits = Some(product(&words)?),
+        "temperature" => material.temperature = number(single()?)?,
+        "conductivity" => material.conductivity = fraction(single()?)?,
+        "melts_at" | "boils_at" => material.heated = Some(phase_change(&words)?),
+        "freezes_at" | "condenses_at" => material.cooled = Some(phase_change(&words)?),
+        _ => return Err("unknown property".to_string()),
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+// This is palette module
+use std::fs;
+
+use sdl2::pixels::Color;
+
+use crate::material::*;
+
+
+// Maps image colours to materials, for building worlds out of pictures
+#[derive(Clone, Debug)]
+pub struct Palette {
+    entries: Vec<(Color, MaterialId)>,
+    // Every material's colour and jitter, what `Material::spawn` can paint
+    shades: Vec<(Color, u8, MaterialId)>,
+}
+
+impl Palette {
+
+    pub fn load(path: &str, materials: &MaterialRegistry) -> Result<Palette, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Palette::parse(&text, materials).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // `r g b [a] material` lines, the materials' own colours come after them
+    pub fn parse(text: &str, materials: &MaterialRegistry) -> Result<Palette, String> {
+        let mut entries = vec![];
+        let mut shades = vec![];
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words : Vec<&str> = line.split_whitespace().collect();
+            let (name, channels) = words.split_last().unwrap();
+            let channels = channels.iter()
+                .map(|w| w.parse::<u8>().map_err(|_| format!("line {}: `{}` is not a colour channel", i+1, w)))
+                .collect::<Result<Vec<u8>, String>>()?;
+            let color = match channels.as_slice() {
+                [r, g, b] => Color::RGBA(*r, *g, *b, 255),
+                [r, g, b, a] => Color::RGBA(*r, *g, *b, *a),
+                _ => return Err(format!("line {}: expected `r g b [a] material`", i+1)),
+            };
+            let id = materials.id(name).ok_or(format!("line {}: unknown material `{}`", i+1, name))?;
+            entries.push((color, id));
+        }
+        for material in materials.iter() {
+            if material.id != EMPTY {
+                entries.push((material.color, material.id));
+                shades.push((material.color, material.color_jitter, material.id));
+            }
+        }
+        Ok(Palette { entries, shades })
+    }
+
+    // A listed colour as it was listed, then a shade some material spawns with
+    // so exported worlds come back as they were, otherwise the closest entry.
+    // Transparent pixels only look at transparent entries.
+    pub fn material(&self, color: Color) -> MaterialId {
+        if let Some((_, id)) = self.entries.iter().find(|(c, _)| *c == color) {
+            return *id;
+        }
+
+        let shade = self.shades.iter()
+            .filter_map(|(base, jitter, id)| shade_offset(*base, *jitter, color).map(|offset| (offset, *id)))
+            .min_by_key(|(offset, _)| offset.abs());
+        if let Some((_, id)) = shade {
+            return id;
+        }
+
+        let transparent = |c: Color| c.a < 128;
+        let distance = |c: Color| {
+            let d = |a: u8, b: u8| (a as i32 - b as i32).pow(2);
+            d(c.r, color.r) + d(c.g, color.g) + d(c.b, color.b) + d(c.a, color.a)
+        };
+        self.entries.iter()
+            .filter(|(c, _)| transparent(*c) == transparent(color))
+            .min_by_key(|(c, _)| distance(*c))
+            .map_or(EMPTY, |(_, id)| *id)
+    }
+}
+
+// Brightness offset `Material::spawn` would have used to paint `color` out of
+// `base`, if it could have
+fn shade_offset(base: Color, jitter: u8, color: Color) -> Option<i32> {
+    if base.a != color.a {
+        return None;
+    }
+    let mut low = -(jitter as i32);
+    let mut high = jitter as i32;
+    for (b, c) in [(base.r, color.r), (base.g, color.g), (base.b, color.b)] {
+        let (b, c) = (b as i32, c as i32);
+        // channels are clamped, a 0 or 255 could come from any offset past it
+        if c > 0 { low = low.max(c - b); }
+        if c < 255 { high = high.min(c - b); }
+    }
+    if low > high {
+        return None;
+    }
+    // the one closest to no offset at all
+    Some(if low > 0 { low } else if high < 0 { high } else { 0 })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Exports only round-trip if no two materials can spawn the same shade, keep
+    // their colour ± jitter apart when adding materials
+    #[test]
+    fn every_shade_comes_back_as_its_material() {
+        let materials = MaterialRegistry::load("assets/materials.txt").unwrap();
+        let palette = Palette::parse("", &materials).unwrap();
+        for material in materials.iter() {
+            if material.id == EMPTY {
+                continue;
+            }
+            let jitter = material.color_jitter as i32;
+            for offset in -jitter..=jitter {
+                let shade = |c: u8| (c as i32 + offset).clamp(0, 255) as u8;
+                let color = Color::RGBA(shade(material.color.r), shade(material.color.g), shade(material.color.b), material.color.a);
+                assert!(palette.material(color) == material.id, "{} shade {:?} imports as something else", material.name, color);
+            }
+        }
+    }
+
+    // A designer's entry is kept even when it is some material's shade
+    #[test]
+    fn listed_colours_win_over_shades() {
+        let materials = MaterialRegistry::load("assets/materials.txt").unwrap();
+        let palette = Palette::parse("204 188 138 water\n", &materials).unwrap();
+        assert!(palette.material(Color::RGBA(204, 188, 138, 255)) == materials.id("water").unwrap());
+        // other sand shades are still sand
+        assert!(palette.material(Color::RGBA(198, 182, 132, 255)) == materials.id("sand").unwrap());
+    }
+}
@@ -0,0 +1,80 @@
+// This is rng module
+use rand::{Error, RngCore, SeedableRng};
+
+
+// Small PCG32 (XSH RR) generator. Unlike ThreadRng its whole state is two
+// integers, so a run can be reproduced from its seed and the state saved.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SimRng {
+    state: u64,
+    inc: u64,
+}
+
+const MULTIPLIER: u64 = 6364136223846793005;
+const DEFAULT_STREAM: u64 = 0xda3e39cb94b95bdb;
+
+impl SimRng {
+
+    pub fn new(seed: u64) -> SimRng {
+        let mut ret = SimRng {
+            state: 0,
+            inc: (DEFAULT_STREAM << 1) | 1,
+        };
+        ret.step();
+        ret.state = ret.state.wrapping_add(seed);
+        ret.step();
+        return ret;
+    }
+
+    // Raw generator state, for saving a world mid-run
+    pub fn state(&self) -> (u64, u64) {
+        (self.state, self.inc)
+    }
+
+    pub fn from_state(state: u64, inc: u64) -> SimRng {
+        SimRng {
+            state: state,
+            inc: inc | 1,
+        }
+    }
+
+    fn step(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(MULTIPLIER).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+}
+
+impl RngCore for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        self.step()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let low = self.step() as u64;
+        let high = self.step() as u64;
+        (high << 32) | low
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(4) {
+            let bytes = self.step().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl SeedableRng for SimRng {
+    type Seed = [u8; 8];
+
+    fn from_seed(seed: [u8; 8]) -> SimRng {
+        SimRng::new(u64::from_le_bytes(seed))
+    }
+}
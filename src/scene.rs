@@ -0,0 +1,205 @@
+// This is scene module
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+use sdl2::image::{LoadSurface, SaveSurface};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::surface::Surface;
+
+use crate::material::*;
+use crate::palette::Palette;
+use crate::world_grid::*;
+
+
+// Palette used for images given as a scene
+pub const PALETTE_PATH: &str = "assets/palette.txt";
+
+// Any scene file, picked by its extension
+pub fn load(path: &str, seed: u64, config: WorldConfig, materials: Arc<MaterialRegistry>) -> Result<World, String> {
+    if path.to_ascii_lowercase().ends_with(".png") {
+        let palette = Palette::load(PALETTE_PATH, &materials)?;
+        load_image(path, &palette, seed, config, materials)
+    } else {
+        load_text(path, seed, config, materials)
+    }
+}
+
+// One cell per pixel, the world takes the size of the image
+pub fn load_image(path: &str, palette: &Palette, seed: u64, config: WorldConfig, materials: Arc<MaterialRegistry>) -> Result<World, String> {
+    let surface = Surface::from_file(path)
+        .and_then(|surface| surface.convert_format(PixelFormatEnum::RGBA32))
+        .map_err(|e| format!("{}: {}", path, e))?;
+    let width = surface.width() as usize;
+    let height = surface.height() as usize;
+    let pitch = surface.pitch() as usize;
+    let mut pixels = vec![0u8; width * height * 4];
+    surface.with_lock(|buffer: &[u8]| {
+        for y in 0..height {
+            pixels[y*width*4..(y+1)*width*4].copy_from_slice(&buffer[y*pitch..y*pitch + width*4]);
+        }
+    });
+    from_pixels(width, height, &pixels, palette, seed, config, materials).map_err(|e| format!("{}: {}", path, e))
+}
+
+// `pixels` is RGBA, row by row
+pub fn from_pixels(width: usize, height: usize, pixels: &[u8], palette: &Palette, seed: u64, config: WorldConfig, materials: Arc<MaterialRegistry>) -> Result<World, String> {
+    if width == 0 || height == 0 {
+        return Err("the world can't be empty".to_string());
+    }
+    if pixels.len() != width * height * 4 {
+        return Err(format!("expected {} bytes of pixels for {}x{}", width * height * 4, width, height));
+    }
+    let mut world = World::with_seed(width, height, seed, config, materials.clone());
+    for y in 0..height {
+        for x in 0..width {
+            let i = (x + y*width) * 4;
+            let color = Color::RGBA(pixels[i], pixels[i+1], pixels[i+2], pixels[i+3]);
+            let material = materials.get(palette.material(color));
+            if material.id == EMPTY {
+                continue;
+            }
+            let mut cell = material.spawn(world.rng());
+            // Shades the material could have spawned with are kept, so exports come back unchanged
+            let jitter = material.color_jitter as i32;
+            let close = |a: u8, b: u8| (a as i32 - b as i32).abs() <= jitter;
+            if color.a == material.color.a
+                && close(color.r, material.color.r)
+                && close(color.g, material.color.g)
+                && close(color.b, material.color.b)
+            {
+                cell.data_mut().unwrap().color = color;
+            }
+            world.set(x, y, cell);
+        }
+    }
+    Ok(world)
+}
+
+// One pixel per cell in its own colour, reads back with `load_image`
+pub fn save_image(world: &World, path: &str) -> Result<(), String> {
+    let mut pixels = to_pixels(world);
+    let width = world.width() as u32;
+    let height = world.height() as u32;
+    let surface = Surface::from_data(&mut pixels, width, height, width * 4, PixelFormatEnum::RGBA32)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    surface.save(path).map_err(|e| format!("{}: {}", path, e))
+}
+
+// RGBA, row by row, no canvas needed
+pub fn to_pixels(world: &World) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(world.width() * world.height() * 4);
+    for y in 0..world.height() {
+        for x in 0..world.width() {
+            let color = world.get(x, y).color();
+            ret.extend_from_slice(&[color.r, color.g, color.b, color.a]);
+        }
+    }
+    ret
+}
+
+// Plain text world layouts, easy to draw by hand and to diff:
+//
+//     size 40 20
+//     material . empty
+//     material # block
+//     material s sand
+//     grid
+//     ....sss....
+//     ###########
+//
+// Rows shorter than the width, and missing rows, are empty.
+pub fn load_text(path: &str, seed: u64, config: WorldConfig, materials: Arc<MaterialRegistry>) -> Result<World, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    parse_text(&text, seed, config, materials).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn parse_text(text: &str, seed: u64, config: WorldConfig, materials: Arc<MaterialRegistry>) -> Result<World, String> {
+    let mut size = None;
+    let mut legend = HashMap::<char, MaterialId>::new();
+    let mut lines = text.lines().enumerate();
+
+    for (i, line) in lines.by_ref() {
+        let words : Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => continue,
+            ["size", width, height] => {
+                let width = width.parse::<usize>().map_err(|_| format!("line {}: bad width", i+1))?;
+                let height = height.parse::<usize>().map_err(|_| format!("line {}: bad height", i+1))?;
+                if width == 0 || height == 0 {
+                    return Err(format!("line {}: the world can't be empty", i+1));
+                }
+                size = Some((width, height));
+            },
+            ["material", symbol, name] if symbol.chars().count() == 1 => {
+                let id = materials.id(name).ok_or(format!("line {}: unknown material `{}`", i+1, name))?;
+                legend.insert(symbol.chars().next().unwrap(), id);
+            },
+            ["grid"] => break,
+            _ => return Err(format!("line {}: expected `size`, `material` or `grid`", i+1)),
+        }
+    }
+
+    let (width, height) = size.ok_or("missing `size <width> <height>`".to_string())?;
+    let mut world = World::with_seed(width, height, seed, config, materials.clone());
+    for (y, (i, line)) in lines.enumerate() {
+        if y >= height {
+            return Err(format!("line {}: more rows than the world height", i+1));
+        }
+        for (x, symbol) in line.chars().enumerate() {
+            if x >= width {
+                return Err(format!("line {}: row longer than the world width", i+1));
+            }
+            let id = *legend.get(&symbol).ok_or(format!("line {}: `{}` is not in the legend", i+1, symbol))?;
+            if id != EMPTY {
+                let cell = materials.get(id).spawn(world.rng());
+                world.set(x, y, cell);
+            }
+        }
+    }
+    Ok(world)
+}
+
+pub fn save_text(world: &World, path: &str) -> Result<(), String> {
+    fs::write(path, to_text(world)).map_err(|e| format!("{}: {}", path, e))
+}
+
+pub fn to_text(world: &World) -> String {
+    let materials = world.materials();
+
+    // Symbols are picked for the materials actually present, by their initials when possible
+    let mut symbols = HashMap::<MaterialId, char>::new();
+    symbols.insert(EMPTY, '.');
+    let fallback = "0123456789@$%&*+=-~^:;!?<>/|";
+    for y in 0..world.height() {
+        for x in 0..world.width() {
+            let id = world.get(x, y).material();
+            if symbols.contains_key(&id) {
+                continue;
+            }
+            let name = &materials.get(id).name;
+            let symbol = name.chars()
+                .flat_map(|c| [c.to_ascii_lowercase(), c.to_ascii_uppercase()])
+                .chain(fallback.chars())
+                .find(|c| !c.is_whitespace() && !symbols.values().any(|used| used == c))
+                .unwrap_or('?');
+            symbols.insert(id, symbol);
+        }
+    }
+
+    let mut legend : Vec<(&MaterialId, &char)> = symbols.iter().collect();
+    legend.sort();
+
+    let mut ret = format!("size {} {}\n", world.width(), world.height());
+    for (id, symbol) in legend {
+        ret += &format!("material {} {}\n", symbol, materials.get(*id).name);
+    }
+    ret += "grid\n";
+    for y in 0..world.height() {
+        for x in 0..world.width() {
+            ret.push(symbols[&world.get(x, y).material()]);
+        }
+        ret.push('\n');
+    }
+    ret
+}
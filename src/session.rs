@@ -0,0 +1,287 @@
+// This is session module
+use std::fs;
+use std::sync::Arc;
+
+use crate::datatype::Vector2;
+use crate::history::History;
+use crate::material::*;
+use crate::scene;
+use crate::toolbox::*;
+use crate::world_grid::*;
+
+
+// Everything the user can do to the world, in grid coordinates so it doesn't
+// depend on the window
+#[derive(Clone, PartialEq)]
+pub enum Action {
+    // One tick of the current tool centred on a cell
+    Apply(Vector2<usize>),
+    // One tick of the eraser, whatever the current tool
+    Erase(Vector2<usize>),
+    // The current shape tool from one cell to another
+    Draw(Vector2<usize>, Vector2<usize>),
+    SelectTool(Tool),
+    SelectMaterial(MaterialId),
+    // Colour mode and palette index
+    SelectColor(ColorMode, usize),
+    // Shape, size and particles per tick
+    SetBrush(BrushShape, usize, usize),
+    // Mouse down and up, what's painted in between is undone together
+    BeginStroke,
+    EndStroke,
+    Undo,
+    Redo,
+    // The whole world back to before the last stroke
+    Revert,
+}
+
+impl Action {
+
+    pub fn perform(&self, world: &mut World, toolbox: &mut ToolBox, history: &mut History) {
+        match self {
+            Action::Apply(center) => toolbox.apply(world, *center),
+            Action::Erase(center) => toolbox.apply_tool(world, Tool::Erase, *center),
+            Action::Draw(from, to) => toolbox.draw_shape(world, *from, *to),
+            Action::SelectTool(tool) => toolbox.set_tool(*tool),
+            Action::SelectMaterial(material) => toolbox.set_material(*material),
+            Action::SelectColor(mode, index) => toolbox.set_color(*mode, *index),
+            Action::SetBrush(shape, size, points) => {
+                toolbox.set_brush_shape(*shape);
+                toolbox.set_brush_size(*size);
+                toolbox.set_points_per_paint(*points);
+            },
+            Action::BeginStroke => {
+                toolbox.lift();
+                history.begin_stroke(world);
+            },
+            Action::EndStroke => {
+                toolbox.lift();
+                history.end_stroke(world);
+            },
+            Action::Undo => {
+                history.undo(world);
+            },
+            Action::Redo => {
+                history.redo(world);
+            },
+            Action::Revert => {
+                history.revert(world);
+            },
+        }
+    }
+}
+
+
+// A recorded run: how the world started and every action with its frame.
+// With the seeded rng, replaying it gives back the same world frame for frame.
+#[derive(Clone)]
+pub struct Session {
+    seed: u64,
+    size: Vector2<usize>,
+    scene: Option<String>,
+    config: WorldConfig,
+    // Sorted by frame, actions of a frame keep their order
+    actions: Vec<(usize, Action)>,
+    frames: usize,
+}
+
+impl Session {
+
+    pub fn new(world: &World, scene: Option<&str>) -> Session {
+        Session {
+            seed: world.seed(),
+            size: Vector2 { x: world.width(), y: world.height() },
+            scene: scene.map(|path| path.to_string()),
+            config: world.config().clone(),
+            actions: vec![],
+            frames: 0,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Frames the session lasted
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn set_frames(&mut self, frames: usize) {
+        self.frames = frames;
+    }
+
+    pub fn record(&mut self, frame: usize, action: Action) {
+        self.frames = self.frames.max(frame + 1);
+        self.actions.push((frame, action));
+    }
+
+    // What was done during `frame`, in order
+    pub fn actions_at(&self, frame: usize) -> impl Iterator<Item = &Action> {
+        let start = self.actions.partition_point(|(f, _)| *f < frame);
+        self.actions[start..].iter()
+            .take_while(move |(f, _)| *f == frame)
+            .map(|(_, action)| action)
+    }
+
+    // The world as it was when recording started
+    pub fn start_world(&self, materials: Arc<MaterialRegistry>) -> Result<World, String> {
+        match &self.scene {
+            Some(path) => scene::load(path, self.seed, self.config.clone(), materials),
+            None => Ok(World::with_seed(self.size.x, self.size.y, self.seed, self.config.clone(), materials)),
+        }
+    }
+
+    pub fn load(path: &str, materials: &MaterialRegistry) -> Result<Session, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        Session::parse(&text, materials).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &str, materials: &MaterialRegistry) -> Result<(), String> {
+        fs::write(path, self.to_text(materials)).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    // A header, then one `<frame> <action>` line per action:
+    //
+    //     seed 42
+    //     size 400 300
+    //     scene level.png
+    //     rooms 16 16
+    //     cooldown 12
+    //     gravity 0.15
+    //     max-fall-speed 8
+    //     slide 2 4
+    //     frames 950
+    //     12 material water
+    //     12 tool paint
+    //     13 stroke begin
+    //     13 apply 200 40
+    //     14 erase 200 40
+    //     30 draw 10 10 50 40
+    //     20 stroke end
+    //     25 undo
+    //
+    // `scene` is optional, so are the `WorldConfig` lines which default to
+    // `WorldConfig::default()`; `room-size <width> <height>` can replace `rooms`.
+    // `tool` is any of `Tool::name()`. The other actions are
+    // `redo`, `revert`, `color natural|pulse|palette <index>` and
+    // `brush <shape> <size> <particles per tick>`.
+    pub fn parse(text: &str, materials: &MaterialRegistry) -> Result<Session, String> {
+        let mut seed = None;
+        let mut size = None;
+        let mut ret = Session {
+            seed: 0,
+            size: Vector2 { x: 0, y: 0 },
+            scene: None,
+            config: WorldConfig::default(),
+            actions: vec![],
+            frames: 0,
+        };
+        for (i, line) in text.lines().enumerate() {
+            let words : Vec<&str> = line.split_whitespace().collect();
+            let number = |word: &str| -> Result<usize, String> {
+                word.parse::<usize>().map_err(|_| format!("line {}: `{}` is not a number", i+1, word))
+            };
+            match words.as_slice() {
+                [] => continue,
+                ["seed", value] => {
+                    seed = Some(value.parse::<u64>().map_err(|_| format!("line {}: bad seed", i+1))?);
+                },
+                ["size", width, height] => {
+                    let (width, height) = (number(width)?, number(height)?);
+                    if width == 0 || height == 0 {
+                        return Err(format!("line {}: the world can't be empty", i+1));
+                    }
+                    size = Some(Vector2 { x: width, y: height });
+                },
+                ["scene", path] => ret.scene = Some(path.to_string()),
+                ["rooms", x, y] => ret.config.rooms = RoomLayout::Count(Vector2 { x: number(x)?, y: number(y)? }),
+                ["room-size", x, y] => ret.config.rooms = RoomLayout::Size(Vector2 { x: number(x)?, y: number(y)? }),
+                ["cooldown", frames] => ret.config.hotness_cooldown = number(frames)?,
+                ["gravity", value] => {
+                    ret.config.gravity = value.parse::<f32>().map_err(|_| format!("line {}: bad gravity", i+1))?;
+                },
+                ["max-fall-speed", value] => {
+                    ret.config.max_fall_speed = value.parse::<f32>().map_err(|_| format!("line {}: bad max fall speed", i+1))?;
+                },
+                ["slide", from, to] => ret.config.slide_range = number(from)?..=number(to)?,
+                ["frames", frames] => ret.frames = ret.frames.max(number(frames)?),
+                [frame, "apply", x, y] => {
+                    ret.record(number(frame)?, Action::Apply(Vector2 { x: number(x)?, y: number(y)? }));
+                },
+                [frame, "erase", x, y] => {
+                    ret.record(number(frame)?, Action::Erase(Vector2 { x: number(x)?, y: number(y)? }));
+                },
+                [frame, "draw", x0, y0, x1, y1] => {
+                    let from = Vector2 { x: number(x0)?, y: number(y0)? };
+                    let to = Vector2 { x: number(x1)?, y: number(y1)? };
+                    ret.record(number(frame)?, Action::Draw(from, to));
+                },
+                [frame, "tool", tool] => {
+                    let tool = Tool::from_name(tool).ok_or(format!("line {}: unknown tool `{}`", i+1, tool))?;
+                    ret.record(number(frame)?, Action::SelectTool(tool));
+                },
+                [frame, "color", "natural"] => ret.record(number(frame)?, Action::SelectColor(ColorMode::Natural, 0)),
+                [frame, "color", "pulse"] => ret.record(number(frame)?, Action::SelectColor(ColorMode::Pulse, 0)),
+                [frame, "color", "palette", index] => {
+                    ret.record(number(frame)?, Action::SelectColor(ColorMode::Palette, number(index)?));
+                },
+                [frame, "brush", shape, size, points] => {
+                    let shape = BrushShape::from_name(shape).ok_or(format!("line {}: unknown brush `{}`", i+1, shape))?;
+                    ret.record(number(frame)?, Action::SetBrush(shape, number(size)?, number(points)?));
+                },
+                [frame, "stroke", "begin"] => ret.record(number(frame)?, Action::BeginStroke),
+                [frame, "stroke", "end"] => ret.record(number(frame)?, Action::EndStroke),
+                [frame, "undo"] => ret.record(number(frame)?, Action::Undo),
+                [frame, "redo"] => ret.record(number(frame)?, Action::Redo),
+                [frame, "revert"] => ret.record(number(frame)?, Action::Revert),
+                [frame, "material", name] => {
+                    let id = materials.id(name).ok_or(format!("line {}: unknown material `{}`", i+1, name))?;
+                    ret.record(number(frame)?, Action::SelectMaterial(id));
+                },
+                _ => return Err(format!("line {}: can't read `{}`", i+1, line)),
+            }
+        }
+        ret.seed = seed.ok_or("missing `seed <n>`".to_string())?;
+        ret.size = size.ok_or("missing `size <width> <height>`".to_string())?;
+        ret.config.validate()?;
+        // Stable, so actions within a frame stay in the order they were written
+        ret.actions.sort_by_key(|(frame, _)| *frame);
+        return Ok(ret);
+    }
+
+    pub fn to_text(&self, materials: &MaterialRegistry) -> String {
+        let mut ret = format!("seed {}\nsize {} {}\n", self.seed, self.size.x, self.size.y);
+        if let Some(path) = &self.scene {
+            ret += &format!("scene {}\n", path);
+        }
+        ret += &match self.config.rooms {
+            RoomLayout::Count(count) => format!("rooms {} {}\n", count.x, count.y),
+            RoomLayout::Size(size) => format!("room-size {} {}\n", size.x, size.y),
+        };
+        ret += &format!("cooldown {}\n", self.config.hotness_cooldown);
+        ret += &format!("gravity {}\n", self.config.gravity);
+        ret += &format!("max-fall-speed {}\n", self.config.max_fall_speed);
+        ret += &format!("slide {} {}\n", self.config.slide_range.start(), self.config.slide_range.end());
+        ret += &format!("frames {}\n", self.frames);
+        for (frame, action) in self.actions.iter() {
+            ret += &match action {
+                Action::Apply(center) => format!("{} apply {} {}\n", frame, center.x, center.y),
+                Action::Erase(center) => format!("{} erase {} {}\n", frame, center.x, center.y),
+                Action::Draw(from, to) => format!("{} draw {} {} {} {}\n", frame, from.x, from.y, to.x, to.y),
+                Action::SelectTool(tool) => format!("{} tool {}\n", frame, tool.name()),
+                Action::SelectMaterial(id) => format!("{} material {}\n", frame, materials.get(*id).name),
+                Action::SelectColor(ColorMode::Natural, _) => format!("{} color natural\n", frame),
+                Action::SelectColor(ColorMode::Pulse, _) => format!("{} color pulse\n", frame),
+                Action::SelectColor(ColorMode::Palette, index) => format!("{} color palette {}\n", frame, index),
+                Action::SetBrush(shape, size, points) => format!("{} brush {} {} {}\n", frame, shape.name(), size, points),
+                Action::BeginStroke => format!("{} stroke begin\n", frame),
+                Action::EndStroke => format!("{} stroke end\n", frame),
+                Action::Undo => format!("{} undo\n", frame),
+                Action::Redo => format!("{} redo\n", frame),
+                Action::Revert => format!("{} revert\n", frame),
+            };
+        }
+        ret
+    }
+}
@@ -1,39 +1,257 @@
+use rand::Rng;
 use sdl2::pixels::Color;
 use sdl2::event::Event;
+use std::cmp::{max, min};
 use crate::datatype::*;
+use crate::material::*;
+use crate::world_grid::*;
 
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tool {
+    Paint,
+    Erase,
+    // Only clears cells of the selected material
+    EraseMaterial,
+    // Shapes, previewed while dragging and drawn on release
+    Line,
+    Rectangle,
+    FilledRectangle,
+    Circle,
+    FilledCircle,
+    // Fills the connected cells of the same material as the one clicked
+    Bucket,
+}
+
+impl Tool {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Tool::Paint => "paint",
+            Tool::Erase => "erase",
+            Tool::EraseMaterial => "erase-material",
+            Tool::Line => "line",
+            Tool::Rectangle => "rectangle",
+            Tool::FilledRectangle => "filled-rectangle",
+            Tool::Circle => "circle",
+            Tool::FilledCircle => "filled-circle",
+            Tool::Bucket => "bucket",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Tool> {
+        [
+            Tool::Paint, Tool::Erase, Tool::EraseMaterial,
+            Tool::Line, Tool::Rectangle, Tool::FilledRectangle, Tool::Circle, Tool::FilledCircle,
+            Tool::Bucket,
+        ]
+            .into_iter()
+            .find(|tool| tool.name() == name)
+    }
+
+    // Drawn from where the mouse went down to where it goes up
+    pub fn is_shape(&self) -> bool {
+        matches!(self, Tool::Line | Tool::Rectangle | Tool::FilledRectangle | Tool::Circle | Tool::FilledCircle)
+    }
+}
+
+// How painted particles are coloured
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ColorMode {
+    // The material's own colour and shades
+    Natural,
+    // The selected one of `available_colors`
+    Palette,
+    // Fades in and out of red as you paint
+    Pulse,
+}
+
+// Where a tool tick lands inside the brush box
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BrushShape {
+    // Sprayed over the whole box
+    Square,
+    // Sprayed over the disc inside the box
+    Circle,
+    // Every cell of the box's middle row
+    Line,
+    // Every cell of the box
+    Fill,
+}
+
+impl BrushShape {
+    pub fn name(&self) -> &'static str {
+        match self {
+            BrushShape::Square => "square",
+            BrushShape::Circle => "circle",
+            BrushShape::Line => "line",
+            BrushShape::Fill => "fill",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<BrushShape> {
+        [BrushShape::Square, BrushShape::Circle, BrushShape::Line, BrushShape::Fill]
+            .into_iter()
+            .find(|shape| shape.name() == name)
+    }
+
+    pub fn next(&self) -> BrushShape {
+        match self {
+            BrushShape::Square => BrushShape::Circle,
+            BrushShape::Circle => BrushShape::Line,
+            BrushShape::Line => BrushShape::Fill,
+            BrushShape::Fill => BrushShape::Square,
+        }
+    }
+}
+
+pub const MAX_BRUSH_SIZE: usize = 99;
+pub const MAX_POINTS_PER_PAINT: usize = 500;
+
+// Materials on the number keys 1 to 9 then 0, when they exist
+const HOTBAR: [&str; 10] = ["sand", "water", "oil", "fire", "gunpowder", "wood", "ice", "lava", "acid", "block"];
+
 pub struct ToolBox {
+    tool: Tool,
+    material: MaterialId,
+    hotbar: Vec<MaterialId>,
+    color_mode: ColorMode,
     current_color_index: usize,
     available_colors: Vec<Color>,
+    brush_shape: BrushShape,
     mouse_box: Vector2<usize>,
     points_per_paint : usize,
+    // Paint ticks so far, drives the colour of painted particles
+    tick_counter: usize,
+    // Where the previous tick of the stroke landed, the gap to the next one gets filled
+    last_position: Option<Vector2<usize>>,
 }
 
 impl ToolBox {
-    pub fn new() -> ToolBox {
+    pub fn new(materials: &MaterialRegistry) -> ToolBox {
+        let hotbar : Vec<MaterialId> = HOTBAR.iter().filter_map(|name| materials.id(name)).collect();
         ToolBox {
+            tool: Tool::Paint,
+            material: hotbar.first().copied().unwrap_or(EMPTY),
+            hotbar: hotbar,
+            color_mode: ColorMode::Natural,
             current_color_index: 0,
             available_colors: vec![
-                Color::RGB(255, 255, 255),
                 Color::RGB(0, 0, 0),
+                Color::RGB(255, 200, 0),
                 Color::RGB(255, 0, 0),
                 Color::RGB(0, 255, 0),
                 Color::RGB(0, 0, 255),
             ],
+            brush_shape: BrushShape::Square,
             mouse_box: Vector2{x:15, y:15},
             points_per_paint: 55,
+            tick_counter: 0,
+            last_position: None,
+        }
+    }
+
+    pub fn tool(&self) -> Tool {
+        self.tool
+    }
+
+    pub fn set_tool(&mut self, tool: Tool) {
+        self.tool = tool;
+    }
+
+    // Material painted by Tool::Paint
+    pub fn material(&self) -> MaterialId {
+        self.material
+    }
+
+    pub fn set_material(&mut self, material: MaterialId) {
+        self.material = material;
+    }
+
+    // Materials for the number keys, in order
+    pub fn hotbar(&self) -> &Vec<MaterialId> {
+        &self.hotbar
+    }
+
+    pub fn color_mode(&self) -> ColorMode {
+        self.color_mode
+    }
+
+    pub fn set_color(&mut self, mode: ColorMode, index: usize) {
+        self.color_mode = mode;
+        self.current_color_index = index.min(self.available_colors.len() - 1);
+    }
+
+    // What comes after the current colour: natural, each palette colour, pulse, and around
+    pub fn next_color(&self) -> (ColorMode, usize) {
+        match self.color_mode {
+            ColorMode::Natural => (ColorMode::Palette, 0),
+            ColorMode::Palette if self.current_color_index + 1 < self.available_colors.len() => {
+                (ColorMode::Palette, self.current_color_index + 1)
+            },
+            ColorMode::Palette => (ColorMode::Pulse, 0),
+            ColorMode::Pulse => (ColorMode::Natural, 0),
         }
     }
 
+    pub fn brush_shape(&self) -> BrushShape {
+        self.brush_shape
+    }
+
+    pub fn set_brush_shape(&mut self, shape: BrushShape) {
+        self.brush_shape = shape;
+    }
+
     pub fn mouse_box(&self) -> Vector2<usize> {
         self.mouse_box
     }
 
+    // The brush box is always square, 1 to MAX_BRUSH_SIZE cells wide
+    pub fn set_brush_size(&mut self, size: usize) {
+        let size = size.clamp(1, MAX_BRUSH_SIZE);
+        self.mouse_box = Vector2{x: size, y: size};
+    }
+
+    // Particles sprayed per tick
     pub fn points_per_paint(&self) -> usize {
         self.points_per_paint
     }
 
+    pub fn set_points_per_paint(&mut self, points: usize) {
+        self.points_per_paint = points.clamp(1, MAX_POINTS_PER_PAINT);
+    }
+
+    // Cells under the brush centred on a grid cell. The box is moved to stay
+    // inside the world rather than cut.
+    pub fn cells(&self, center: Vector2<usize>, width: usize, height: usize) -> Vec<Vector2<usize>> {
+        let size = Vector2{x: min(self.mouse_box.x, width), y: min(self.mouse_box.y, height)};
+        let mut center_x = max(size.x/2, center.x);
+        let mut center_y = max(size.y/2, center.y);
+        center_x = min(center_x, width - (size.x - size.x/2));
+        center_y = min(center_y, height - (size.y - size.y/2));
+        let left = center_x - size.x/2;
+        let top = center_y - size.y/2;
+
+        let mut ret = vec![];
+        for y in (top..top + size.y) {
+            for x in (left..left + size.x) {
+                let inside = match self.brush_shape {
+                    BrushShape::Square | BrushShape::Fill => true,
+                    BrushShape::Line => y == center_y,
+                    BrushShape::Circle => {
+                        // Measured from cell centres, so even sizes stay symmetric
+                        let dx = ((x - left) as f32 + 0.5) / size.x as f32 - 0.5;
+                        let dy = ((y - top) as f32 + 0.5) / size.y as f32 - 0.5;
+                        dx*dx + dy*dy <= 0.25
+                    },
+                };
+                if (inside) {
+                    ret.push(Vector2{x: x, y: y});
+                }
+            }
+        }
+        return ret;
+    }
+
     pub fn current_color_index(&self) -> usize {
         self.current_color_index
     }
@@ -46,4 +264,185 @@ impl ToolBox {
         &self.available_colors[self.current_color_index as usize]
     }
 
-}
\ No newline at end of file
+    // One tick of the current tool with the box centred on a grid cell, kept
+    // inside the world. Only uses the world's rng, so it replays exactly.
+    pub fn apply(&mut self, world: &mut World, center: Vector2<usize>) {
+        self.apply_tool(world, self.tool, center);
+    }
+
+    // Same with another tool, like the eraser on the right button. Within a
+    // stroke the brush is put down along the way from the previous position,
+    // about half a brush apart (every cell for the one row thick line), so fast
+    // moves leave no gaps and paint as much as slow ones.
+    pub fn apply_tool(&mut self, world: &mut World, tool: Tool, center: Vector2<usize>) {
+        let path = match self.last_position {
+            Some(last) if last != center => line(last, center).split_off(1),
+            _ => vec![center],
+        };
+        self.last_position = Some(center);
+        let spacing = match self.brush_shape {
+            BrushShape::Line => 1,
+            _ => max(1, self.mouse_box.x / 2),
+        };
+        let last_index = path.len() - 1;
+        for (i, position) in path.into_iter().enumerate() {
+            if ((i + 1) % spacing == 0 || i == last_index) {
+                self.stamp(world, tool, position);
+            }
+        }
+    }
+
+    // Ends the stroke, the next tick starts a new one wherever it is
+    pub fn lift(&mut self) {
+        self.last_position = None;
+    }
+
+    // Cells the current shape tool covers between two positions, inside the world.
+    // Positions off the world are pulled back onto its edge first, so a bogus
+    // replay can't ask for a shape bigger than the world.
+    pub fn shape_cells(&self, from: Vector2<usize>, to: Vector2<usize>, width: usize, height: usize) -> Vec<Vector2<usize>> {
+        let clamp = |cell: Vector2<usize>| Vector2{x: min(cell.x, width - 1), y: min(cell.y, height - 1)};
+        let (from, to) = (clamp(from), clamp(to));
+        let radius = || {
+            let dx = from.x as f32 - to.x as f32;
+            let dy = from.y as f32 - to.y as f32;
+            (dx*dx + dy*dy).sqrt().round() as usize
+        };
+        let inside = |cell: &Vector2<i64>| cell.x >= 0 && cell.y >= 0 && cell.x < width as i64 && cell.y < height as i64;
+        let ret = match self.tool {
+            Tool::Line => line(from, to),
+            Tool::Rectangle => rectangle(from, to, false),
+            Tool::FilledRectangle => rectangle(from, to, true),
+            Tool::Circle | Tool::FilledCircle => {
+                circle(from, radius(), self.tool == Tool::FilledCircle).iter()
+                    .filter(|cell| inside(cell))
+                    .map(|cell| Vector2{x: cell.x as usize, y: cell.y as usize})
+                    .collect()
+            },
+            _ => vec![],
+        };
+        ret.into_iter().filter(|cell| cell.x < width && cell.y < height).collect()
+    }
+
+    // Puts the current shape tool's shape in the world, every cell of it
+    pub fn draw_shape(&mut self, world: &mut World, from: Vector2<usize>, to: Vector2<usize>) {
+        let materials = world.materials().clone();
+        let material = materials.get(self.material);
+        let color = self.tick_color();
+        for cell in self.shape_cells(from, to, world.width(), world.height()) {
+            let new_cell = ToolBox::spawn(material, color, world);
+            world.set(cell.x, cell.y, new_cell);
+        }
+    }
+
+    // Counts a paint tick, and the colour painted cells get instead of their own
+    fn tick_color(&mut self) -> Option<Color> {
+        self.tick_counter += 1;
+        match self.color_mode {
+            ColorMode::Natural => None,
+            ColorMode::Palette => Some(*self.get_current_color()),
+            ColorMode::Pulse => Some(Color::RGBA(
+                if (self.tick_counter % 512 >= 256) { 
+                    (self.tick_counter % 256) as u8
+                } else {
+                    255u8 - (self.tick_counter % 256) as u8
+                },
+                0,
+                0,
+                255
+            )),
+        }
+    }
+
+    fn spawn(material: &Material, color: Option<Color>, world: &mut World) -> CellType {
+        let mut cell = material.spawn(world.rng());
+        if let (Some(data), Some(color)) = (cell.data_mut(), color) {
+            data.color = color;
+        }
+        cell
+    }
+
+    // Every cell 4-connected to `start` with its material becomes the selected material
+    fn flood_fill(&mut self, world: &mut World, start: Vector2<usize>) {
+        if start.x >= world.width() || start.y >= world.height() {
+            return;
+        }
+        let materials = world.materials().clone();
+        let material = materials.get(self.material);
+        let target = world.get(start.x, start.y).material();
+        if target == material.id {
+            return;
+        }
+        let color = self.tick_color();
+        let mut seen = GridMap::new(world.width(), world.height(), false);
+        let mut stack = vec![start];
+        seen.set(start.x, start.y, true);
+        while let Some(cell) = stack.pop() {
+            let new_cell = ToolBox::spawn(material, color, world);
+            world.set(cell.x, cell.y, new_cell);
+            let neighbours = [
+                (cell.x.wrapping_sub(1), cell.y),
+                (cell.x + 1, cell.y),
+                (cell.x, cell.y.wrapping_sub(1)),
+                (cell.x, cell.y + 1),
+            ];
+            for (x, y) in neighbours {
+                if x < world.width() && y < world.height() && !*seen.get(x, y) && world.get(x, y).material() == target {
+                    seen.set(x, y, true);
+                    stack.push(Vector2{x: x, y: y});
+                }
+            }
+        }
+    }
+
+    // The brush once at a single position
+    fn stamp(&mut self, world: &mut World, tool: Tool, center: Vector2<usize>) {
+        let width = world.width();
+        let height = world.height();
+        let materials = world.materials().clone();
+        let material = materials.get(self.material);
+        let color = self.tick_color();
+        let spawn = |world: &mut World| ToolBox::spawn(material, color, world);
+
+        let cells = self.cells(center, width, height);
+
+        match tool {
+            Tool::Erase => {
+                for cell in cells.iter() {
+                    world.set(cell.x, cell.y, CellType::Empty);
+                }
+            },
+            Tool::EraseMaterial => {
+                for cell in cells.iter() {
+                    if world.get(cell.x, cell.y).material() == material.id {
+                        world.set(cell.x, cell.y, CellType::Empty);
+                    }
+                }
+            },
+            // Solids fill the whole brush, walls with holes are no good
+            Tool::Paint if material.state == State::Solid
+                || self.brush_shape == BrushShape::Fill
+                || self.brush_shape == BrushShape::Line => {
+                for cell in cells.iter() {
+                    let new_cell = spawn(world);
+                    world.set(cell.x, cell.y, new_cell);
+                }
+            },
+            Tool::Paint => {
+                for i in (0..self.points_per_paint) {
+                    let cell = cells[world.rng().gen_range( 0..cells.len() )];
+                    let mut new_cell = spawn(world);
+                    // Thrown down a little, gases keep floating up
+                    if let Some(data) = new_cell.data_mut().filter(|_| material.state != State::Gas) {
+                        data.speed = Vector2{x:0.0, y:2.0};
+                    }
+                    world.set(cell.x, cell.y, new_cell);
+                }
+            },
+            Tool::Bucket => self.flood_fill(world, center),
+            // Drawn whole by `draw_shape`
+            Tool::Line | Tool::Rectangle | Tool::FilledRectangle | Tool::Circle | Tool::FilledCircle => {},
+        }
+    }
+
+}
@@ -8,45 +8,218 @@ use std::ops::Div;
 use std::os::unix::thread;
 use std::usize;
 use std::collections::HashSet;
+use std::io::{Read, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
 
-use crate::datatype::{GridMap, Vector2};
+use crate::datatype::{GridMap, SharedGrid, Vector2};
+use crate::material::*;
+use crate::rng::SimRng;
 
 
 #[derive(Clone, PartialEq)]
 pub struct ParticleData {
+    pub material: MaterialId,
     pub speed: Vector2<f32>,
     pub color: Color,
+    // Ticks left before a short-lived particle (gases, fire) decays, 0 for forever
+    pub lifetime: u32,
+}
+
+// Temperature everything settles back to, in °C
+pub const AMBIENT_TEMPERATURE: f32 = 20.0;
+
+// Snapshot file header, bump the version whenever the layout below changes
+const SNAPSHOT_MAGIC: &[u8; 4] = b"SBXW";
+const SNAPSHOT_VERSION: u16 = 3;
+
+// Biggest world a snapshot may ask for, so a corrupt one can't allocate the moon
+const MAX_SNAPSHOT_CELLS: usize = 4096 * 4096;
+
+// Furthest a cell may fall or slide in a tick, already more than any world is wide
+const MAX_MOVE_DISTANCE: usize = 4096;
+
+
+// How the world is cut into rooms, the unit that sleeps when nothing happens in it
+#[derive(Clone, Copy, PartialEq)]
+pub enum RoomLayout {
+    // That many rooms across and down, whatever the world size
+    Count(Vector2<usize>),
+    // Rooms of at most that many cells, as many as the world needs
+    Size(Vector2<usize>),
+}
+
+// Knobs of the simulation, the default is how the sandbox has always behaved
+#[derive(Clone, PartialEq)]
+pub struct WorldConfig {
+    pub rooms: RoomLayout,
+    // Frames a room keeps being processed after something changed in or next to it
+    pub hotness_cooldown: usize,
+    // Added to the falling speed of powders and liquids every tick
+    pub gravity: f32,
+    // Cells per tick, also bounds how far a room reaches into its neighbours
+    pub max_fall_speed: f32,
+    // How far sideways a weighted powder may tumble off a pile, picked at random
+    pub slide_range: RangeInclusive<usize>,
+}
+
+impl Default for WorldConfig {
+    fn default() -> WorldConfig {
+        WorldConfig {
+            rooms: RoomLayout::Count(Vector2 { x: 16, y: 16 }),
+            hotness_cooldown: 12,
+            gravity: 0.15,
+            max_fall_speed: 8.0,
+            slide_range: 2..=4,
+        }
+    }
+}
+
+impl WorldConfig {
+
+    pub fn validate(&self) -> Result<(), String> {
+        let (RoomLayout::Count(rooms) | RoomLayout::Size(rooms)) = self.rooms;
+        if rooms.x == 0 || rooms.y == 0 {
+            return Err("rooms can't be empty".to_string());
+        }
+        if self.hotness_cooldown == 0 {
+            return Err("the hotness cooldown must be at least 1".to_string());
+        }
+        if !(self.gravity.is_finite() && self.gravity >= 0.0) {
+            return Err("gravity must be a positive number".to_string());
+        }
+        if !(self.max_fall_speed.is_finite() && self.max_fall_speed >= 1.0 && self.max_fall_speed <= MAX_MOVE_DISTANCE as f32) {
+            return Err(format!("the max fall speed must be between 1 and {}", MAX_MOVE_DISTANCE));
+        }
+        if *self.slide_range.start() == 0 || self.slide_range.is_empty() {
+            return Err("the slide range must start from 1 or more and not end before it starts".to_string());
+        }
+        if *self.slide_range.end() > MAX_MOVE_DISTANCE {
+            return Err(format!("the slide range can't go past {}", MAX_MOVE_DISTANCE));
+        }
+        Ok(())
+    }
+
+    // Rooms across and down for a world of that size
+    fn room_count(&self, width: usize, height: usize) -> Vector2<usize> {
+        match self.rooms {
+            RoomLayout::Count(count) => count,
+            RoomLayout::Size(size) => Vector2 {
+                x: width.div_ceil(size.x),
+                y: height.div_ceil(size.y),
+            },
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct World {
-    world_rng : ThreadRng,
+    // Every random choice of the simulation goes through it, so a seed replays a run
+    rng: SimRng,
+    seed: u64,
+    materials: Arc<MaterialRegistry>,
+    config: WorldConfig,
     grid_rooms_hotness: GridMap<usize>,
     room_size: Vector2<usize>,
+    // Per room, the cells changed last frame with a cell around them: all the
+    // movement and fire passes look at
+    dirty_rects: GridMap<Option<DirtyRect>>,
+    // The same, being collected for the next frame
+    next_dirty_rects: GridMap<Option<DirtyRect>>,
+    // Generation a cell last moved or reacted in, so it only does once per frame
+    moved: GridMap<u32>,
+    reacted: GridMap<u32>,
+    generation: u32,
     grid: GridMap<CellType>,
+    temperature: GridMap<f32>,
+    water_dispersion: usize,
+    // Threads rooms are processed on, the result doesn't depend on it
+    threads: usize,
+    // Cells changed from outside the simulation, handed to the renderer on the next frame
+    redraw: Vec<Vector2<usize>>,
+    // Cells changed by `set()` while journaling, so they can be undone
+    journal: Option<Vec<CellChange>>,
+}
+
+
+// A cell as it was before and after `set()`
+#[derive(Clone)]
+pub struct CellChange {
+    pub position: Vector2<usize>,
+    pub before: CellType,
+    pub before_temperature: f32,
+    pub after: CellType,
+    pub after_temperature: f32,
 }
 
 
 #[derive(Clone, PartialEq)]
 pub enum CellType {
     Empty,
-    Sand(ParticleData),
-    Block(ParticleData),
+    // What it is and how it behaves comes from the material registry
+    Particle(ParticleData),
+}
+
+impl CellType {
+    pub fn material(&self) -> MaterialId {
+        match self {
+            CellType::Empty => EMPTY,
+            CellType::Particle(data) => data.material,
+        }
+    }
+
+    // How it looks on screen and in exported images, empty is transparent white
+    pub fn color(&self) -> Color {
+        match self {
+            CellType::Empty => Color::RGBA(255, 255, 255, 0),
+            CellType::Particle(data) => data.color,
+        }
+    }
+
+    pub fn data(&self) -> Option<&ParticleData> {
+        match self {
+            CellType::Empty => None,
+            CellType::Particle(data) => Some(data),
+        }
+    }
+
+    pub fn data_mut(&mut self) -> Option<&mut ParticleData> {
+        match self {
+            CellType::Empty => None,
+            CellType::Particle(data) => Some(data),
+        }
+    }
 }
 
 
 impl World {
-    
-    // static single thread rng
 
+    pub fn new(width: usize, height: usize, config: WorldConfig, materials: Arc<MaterialRegistry>) -> World {
+        World::with_seed(width, height, rand::thread_rng().gen(), config, materials)
+    }
 
-    pub fn new(width: usize, height: usize) -> World {
+    // `config` is expected to be valid, see `WorldConfig::validate`
+    pub fn with_seed(width: usize, height: usize, seed: u64, config: WorldConfig, materials: Arc<MaterialRegistry>) -> World {
         let original_grid = GridMap::new(width, height, CellType::Empty);
+        let rooms = config.room_count(width, height);
         let mut ret = World {
-            world_rng: rand::thread_rng(),
+            rng: SimRng::new(seed),
+            seed: seed,
+            materials: materials,
+            config: config,
             grid: original_grid.clone(),
-            grid_rooms_hotness: GridMap::new(16, 16, 0),
+            temperature: GridMap::new(width, height, AMBIENT_TEMPERATURE),
+            grid_rooms_hotness: GridMap::new(rooms.x, rooms.y, 0),
             room_size: Vector2 { x: 0, y: 0 },
+            dirty_rects: GridMap::new(rooms.x, rooms.y, None),
+            next_dirty_rects: GridMap::new(rooms.x, rooms.y, None),
+            moved: GridMap::new(width, height, 0),
+            reacted: GridMap::new(width, height, 0),
+            generation: 0,
+            water_dispersion: 5,
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            redraw: vec![],
+            journal: None,
         };
         ret.room_size = Vector2 {
             x: ret.grid.width().div_ceil(ret.grid_rooms_hotness.width()),
@@ -63,6 +236,46 @@ impl World {
         self.grid.height()
     }
 
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // For random choices made on the world from outside, like painting
+    pub fn rng(&mut self) -> &mut SimRng {
+        &mut self.rng
+    }
+
+    pub fn materials(&self) -> &Arc<MaterialRegistry> {
+        &self.materials
+    }
+
+    pub fn config(&self) -> &WorldConfig {
+        &self.config
+    }
+
+    // Frames each room will still be processed for, 0 when asleep
+    pub fn rooms_hotness(&self) -> &GridMap<usize> {
+        &self.grid_rooms_hotness
+    }
+
+    // Cells per room, the last row and column of rooms may be cut short
+    pub fn room_size(&self) -> Vector2<usize> {
+        self.room_size
+    }
+
+    // How many cells a liquid can walk sideways in a single tick
+    pub fn water_dispersion(&self) -> usize {
+        self.water_dispersion
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
     pub fn get(&self, x:usize, y:usize) -> &CellType {
         self.grid.get(x, y)
     }
@@ -72,22 +285,336 @@ impl World {
     }
 
     pub fn set(&mut self, x: usize, y: usize, cell_type: CellType ) {
+        let temperature = self.materials.get(cell_type.material()).temperature;
+        if let Some(journal) = &mut self.journal {
+            journal.push(CellChange {
+                position: Vector2{x: x, y: y},
+                before: self.grid.get(x, y).clone(),
+                before_temperature: *self.temperature.get(x, y),
+                after: cell_type.clone(),
+                after_temperature: temperature,
+            });
+        }
+        self.temperature.set(x, y, temperature);
         self.grid.set(x, y, cell_type.clone());
         self.hot(x, y);
+        self.redraw.push(Vector2{x: x, y: y});
+    }
+
+    // Starts collecting every `set()`, dropping anything collected so far
+    pub fn start_journal(&mut self) {
+        self.journal = Some(vec![]);
+    }
+
+    // Stops collecting and hands over what was changed, in order
+    pub fn take_journal(&mut self) -> Vec<CellChange> {
+        self.journal.take().unwrap_or_default()
+    }
+
+    pub fn temperature(&self, x:usize, y:usize) -> f32 {
+        *self.temperature.get(x, y)
+    }
+
+    pub fn set_temperature(&mut self, x:usize, y:usize, temperature:f32) {
+        self.temperature.set(x, y, temperature);
+        self.hot(x, y);
+    }
+
+    // Binary snapshot, everything needed to carry on the run exactly where it was:
+    //
+    //   header       magic, version, width, height, seed, rng state, water dispersion
+    //   config       room layout (0 count, 1 size, then x, y), cooldown, gravity,
+    //                max fall speed, slide range
+    //   materials    names in id order, so a reordered materials file still loads
+    //   hotness      room map size and cooldowns
+    //   dirty rects  per room, a flag then left, top, right, bottom
+    //   cells        runs of (count, material, color, speed, lifetime), row by row
+    //   temperature  runs of (count, °C), row by row
+    //
+    // Integers and floats are little endian.
+    pub fn save(&self, out: &mut impl Write) -> Result<(), String> {
+        let mut bytes = Vec::<u8>::new();
+        bytes.extend_from_slice(SNAPSHOT_MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&(self.width() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.height() as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        let (state, inc) = self.rng.state();
+        bytes.extend_from_slice(&state.to_le_bytes());
+        bytes.extend_from_slice(&inc.to_le_bytes());
+        bytes.extend_from_slice(&(self.water_dispersion as u32).to_le_bytes());
+
+        let (layout, rooms) = match self.config.rooms {
+            RoomLayout::Count(count) => (0, count),
+            RoomLayout::Size(size) => (1, size),
+        };
+        bytes.push(layout);
+        bytes.extend_from_slice(&(rooms.x as u32).to_le_bytes());
+        bytes.extend_from_slice(&(rooms.y as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.config.hotness_cooldown as u32).to_le_bytes());
+        bytes.extend_from_slice(&self.config.gravity.to_le_bytes());
+        bytes.extend_from_slice(&self.config.max_fall_speed.to_le_bytes());
+        bytes.extend_from_slice(&(*self.config.slide_range.start() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(*self.config.slide_range.end() as u32).to_le_bytes());
+
+        bytes.extend_from_slice(&(self.materials.len() as u16).to_le_bytes());
+        for material in self.materials.iter() {
+            bytes.push(material.name.len() as u8);
+            bytes.extend_from_slice(material.name.as_bytes());
+        }
+
+        bytes.extend_from_slice(&(self.grid_rooms_hotness.width() as u32).to_le_bytes());
+        bytes.extend_from_slice(&(self.grid_rooms_hotness.height() as u32).to_le_bytes());
+        for y in 0..self.grid_rooms_hotness.height() {
+            for x in 0..self.grid_rooms_hotness.width() {
+                bytes.extend_from_slice(&(*self.grid_rooms_hotness.get(x, y) as u32).to_le_bytes());
+            }
+        }
+        for y in 0..self.next_dirty_rects.height() {
+            for x in 0..self.next_dirty_rects.width() {
+                match self.next_dirty_rects.get(x, y) {
+                    None => bytes.push(0),
+                    Some(rect) => {
+                        bytes.push(1);
+                        for value in [rect.left, rect.top, rect.right, rect.bottom] {
+                            bytes.extend_from_slice(&(value as u32).to_le_bytes());
+                        }
+                    },
+                }
+            }
+        }
+
+        let cells : Vec<&CellType> = (0..self.height())
+            .flat_map(|y| (0..self.width()).map(move |x| (x, y)))
+            .map(|(x, y)| self.get(x, y))
+            .collect();
+        for run in cells.chunk_by(|a, b| a == b) {
+            for part in run.chunks(u32::MAX as usize) {
+                bytes.extend_from_slice(&(part.len() as u32).to_le_bytes());
+                match part[0] {
+                    CellType::Empty => bytes.push(EMPTY),
+                    CellType::Particle(data) => {
+                        bytes.push(data.material);
+                        bytes.extend_from_slice(&[data.color.r, data.color.g, data.color.b, data.color.a]);
+                        bytes.extend_from_slice(&data.speed.x.to_le_bytes());
+                        bytes.extend_from_slice(&data.speed.y.to_le_bytes());
+                        bytes.extend_from_slice(&data.lifetime.to_le_bytes());
+                    },
+                }
+            }
+        }
+
+        let temperatures : Vec<f32> = (0..self.height())
+            .flat_map(|y| (0..self.width()).map(move |x| (x, y)))
+            .map(|(x, y)| self.temperature(x, y))
+            .collect();
+        for run in temperatures.chunk_by(|a, b| a.to_bits() == b.to_bits()) {
+            for part in run.chunks(u32::MAX as usize) {
+                bytes.extend_from_slice(&(part.len() as u32).to_le_bytes());
+                bytes.extend_from_slice(&part[0].to_le_bytes());
+            }
+        }
+
+        out.write_all(&bytes).map_err(|e| e.to_string())
     }
 
+    pub fn load(mut input: impl Read, materials: Arc<MaterialRegistry>) -> Result<World, String> {
+        let mut bytes = Vec::<u8>::new();
+        input.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+        let mut reader = SnapshotReader { bytes: &bytes, pos: 0 };
+
+        if reader.take(4)? != SNAPSHOT_MAGIC {
+            return Err("not a world snapshot".to_string());
+        }
+        let version = u16::from_le_bytes(reader.array()?);
+        if version != SNAPSHOT_VERSION {
+            return Err(format!("unsupported snapshot version {}", version));
+        }
+        let width = reader.u32()? as usize;
+        let height = reader.u32()? as usize;
+        if width == 0 || height == 0 {
+            return Err("the world can't be empty".to_string());
+        }
+        if width.checked_mul(height).map_or(true, |cells| cells > MAX_SNAPSHOT_CELLS) {
+            return Err(format!("a {}x{} world is bigger than the {} cells a snapshot may hold", width, height, MAX_SNAPSHOT_CELLS));
+        }
+        let seed = u64::from_le_bytes(reader.array()?);
+        let state = u64::from_le_bytes(reader.array()?);
+        let inc = u64::from_le_bytes(reader.array()?);
+        let water_dispersion = reader.u32()? as usize;
+
+        let layout = reader.take(1)?[0];
+        let rooms = Vector2 { x: reader.u32()? as usize, y: reader.u32()? as usize };
+        let config = WorldConfig {
+            rooms: match layout {
+                0 => RoomLayout::Count(rooms),
+                1 => RoomLayout::Size(rooms),
+                _ => return Err(format!("unknown room layout {}", layout)),
+            },
+            hotness_cooldown: reader.u32()? as usize,
+            gravity: f32::from_le_bytes(reader.array()?),
+            max_fall_speed: f32::from_le_bytes(reader.array()?),
+            slide_range: (reader.u32()? as usize)..=(reader.u32()? as usize),
+        };
+        config.validate()?;
+        let room_count = config.room_count(width, height);
+        if room_count.x.checked_mul(room_count.y).map_or(true, |rooms| rooms > width * height) {
+            return Err("more rooms than cells".to_string());
+        }
+
+        // Ids in the file are mapped back to ours by name
+        let material_count = u16::from_le_bytes(reader.array()?) as usize;
+        let mut ids = Vec::<MaterialId>::new();
+        for _ in 0..material_count {
+            let len = reader.take(1)?[0] as usize;
+            let name = String::from_utf8_lossy(reader.take(len)?).to_string();
+            ids.push(materials.id(&name).ok_or(format!("unknown material `{}`", name))?);
+        }
+
+        let mut ret = World::with_seed(width, height, seed, config, materials);
+        ret.rng = SimRng::from_state(state, inc);
+        ret.water_dispersion = water_dispersion;
+
+        let rooms_width = reader.u32()? as usize;
+        let rooms_height = reader.u32()? as usize;
+        if rooms_width != ret.grid_rooms_hotness.width() || rooms_height != ret.grid_rooms_hotness.height() {
+            return Err("the room map doesn't match the room layout".to_string());
+        }
+        for y in 0..rooms_height {
+            for x in 0..rooms_width {
+                let hotness = reader.u32()? as usize;
+                ret.grid_rooms_hotness.set(x, y, hotness);
+            }
+        }
+        for y in 0..rooms_height {
+            for x in 0..rooms_width {
+                if reader.take(1)?[0] == 0 {
+                    continue;
+                }
+                let rect = DirtyRect {
+                    left: reader.u32()? as usize,
+                    top: reader.u32()? as usize,
+                    right: reader.u32()? as usize,
+                    bottom: reader.u32()? as usize,
+                };
+                // a room only ever processes its own cells
+                let room_size = ret.room_size;
+                if rect.left > rect.right || rect.top > rect.bottom
+                    || rect.left < x*room_size.x || rect.right >= min(width, (x+1)*room_size.x)
+                    || rect.top < y*room_size.y || rect.bottom >= min(height, (y+1)*room_size.y) {
+                    return Err(format!("bad dirty rect for room ({}, {})", x, y));
+                }
+                ret.next_dirty_rects.set(x, y, Some(rect));
+            }
+        }
+
+        let mut i = 0;
+        while i < width * height {
+            let count = reader.u32()? as usize;
+            let material = reader.take(1)?[0];
+            let cell = if material == EMPTY {
+                CellType::Empty
+            } else {
+                let color = reader.take(4)?;
+                let color = Color::RGBA(color[0], color[1], color[2], color[3]);
+                let speed = Vector2 {
+                    x: f32::from_le_bytes(reader.array()?),
+                    y: f32::from_le_bytes(reader.array()?),
+                };
+                let lifetime = reader.u32()?;
+                CellType::Particle(ParticleData {
+                    material: *ids.get(material as usize).ok_or(format!("bad material id {}", material))?,
+                    speed: speed,
+                    color: color,
+                    lifetime: lifetime,
+                })
+            };
+            if count == 0 || i + count > width * height {
+                return Err("bad cell run".to_string());
+            }
+            for j in i..i + count {
+                ret.grid.set(j % width, j / width, cell.clone());
+            }
+            i += count;
+        }
+
+        let mut i = 0;
+        while i < width * height {
+            let count = reader.u32()? as usize;
+            let temperature = f32::from_le_bytes(reader.array()?);
+            if count == 0 || i + count > width * height {
+                return Err("bad temperature run".to_string());
+            }
+            for j in i..i + count {
+                ret.temperature.set(j % width, j / width, temperature);
+            }
+            i += count;
+        }
+
+        // The whole picture changed
+        ret.redraw = (0..height)
+            .flat_map(|y| (0..width).map(move |x| Vector2{x: x, y: y}))
+            .collect();
+        return Ok(ret);
+    }
+
+    // Wakes the rooms around a changed cell, and puts the cell and its
+    // neighbours in the next frame's dirty rects
     fn hot(&mut self, x: usize, y: usize) {
         self.grid_rooms_hotness.set_neighbor(
             (x/self.room_size.x) as i32,
             (y/self.room_size.y) as i32,
-            12,
+            self.config.hotness_cooldown,
         );
+        for nx in (max(1, x)-1..=min(x+1, self.width()-1)) {
+            for ny in (max(1, y)-1..=min(y+1, self.height()-1)) {
+                let room_x = nx / self.room_size.x;
+                let room_y = ny / self.room_size.y;
+                match self.next_dirty_rects.get_mut(room_x, room_y) {
+                    Some(rect) => rect.include(nx, ny),
+                    rect => *rect = Some(DirtyRect { left: nx, top: ny, right: nx, bottom: ny }),
+                }
+            }
+        }
     }
 
+    // The simulation goes through three passes over the hot rooms: movement,
+    // fire, then heat. Each pass runs in four checkerboard phases, rooms of a
+    // phase are two rooms apart so they can be processed at the same time as
+    // long as none reaches further than half a room (see `reach`). Every room
+    // draws from its own rng, seeded from the world's once per frame, so the
+    // result is the same on any number of threads.
     pub fn process_frame(&mut self) -> Vec<Vector2<usize>> {
 
         let mut ret = vec![];
+        let frame_seed = self.rng.next_u64();
+        self.generation = self.generation.wrapping_add(1);
+        std::mem::swap(&mut self.dirty_rects, &mut self.next_dirty_rects);
+        self.next_dirty_rects.iter_mut().for_each(|rect| *rect = None);
         
+        ret.extend( self.process_pass(0, frame_seed, |view, room_x, room_y, rng| {
+            view.process_room(room_x, room_y, rng)
+        }) );
+
+        // Neighbour interactions run after everything has moved
+        ret.extend( self.process_pass(1, frame_seed, |view, room_x, room_y, rng| {
+            view.process_interactions(room_x, room_y, rng)
+        }) );
+
+        // Heat spreads from a snapshot so the result doesn't depend on scan order.
+        // Rooms still warming up stay awake without anything in them changing.
+        let previous_temperature = self.temperature.clone();
+        let warming = Mutex::new(vec![]);
+        ret.extend( self.process_pass(2, frame_seed, |view, room_x, room_y, rng| {
+            let (changed, still_warming) = view.process_temperature(room_x, room_y, &previous_temperature, rng);
+            if still_warming {
+                warming.lock().unwrap().push((room_x, room_y));
+            }
+            changed
+        }) );
+
+        // Cooling down only once the rooms had their frame, then whatever changed
+        // heats its room up again
         self.grid_rooms_hotness.iter_mut().for_each(|x| {
             if *x > 0 { 
                 *x = *x-1;
@@ -97,174 +624,878 @@ impl World {
                 // }
             }
         });
+        for (room_x, room_y) in warming.into_inner().unwrap() {
+            self.grid_rooms_hotness.set_neighbor(room_x as i32, room_y as i32, self.config.hotness_cooldown);
+        }
+        for cell in ret.iter() {
+            self.hot(cell.x, cell.y);
+        }
+        // Already heated by whoever changed them, redrawing alone must not wake rooms up
+        ret.append(&mut self.redraw);
 
+        return ret;
+    }
 
-        for room_x in (0..self.grid_rooms_hotness.width()) {
-            for room_y in (0..self.grid_rooms_hotness.height()) {
-                if (*self.grid_rooms_hotness.get(room_x, room_y) <= 0 ) {
-                    continue;
-                } else {
-                    ret.extend( self.process_room(room_x, room_y ) );
+    // Runs `process` on every hot room, phase by phase, returning the changed cells
+    // in room order whatever the threads did
+    fn process_pass<F>(&mut self, pass: u64, frame_seed: u64, process: F) -> Vec<Vector2<usize>>
+    where F: Fn(&RoomView, usize, usize, &mut SimRng) -> Vec<Vector2<usize>> + Sync
+    {
+        let mut ret = vec![];
+        let parallel = (self.threads > 1) && (self.reach().saturating_mul(2) <= min(self.room_size.x, self.room_size.y));
+        let threads = self.threads;
+        let room_rng = |room_x: usize, room_y: usize| {
+            let room = (pass << 32) | ((room_y as u64) << 16) | room_x as u64;
+            SimRng::new(frame_seed ^ room.wrapping_mul(0x9e3779b97f4a7c15))
+        };
+
+        let rooms_hotness = &self.grid_rooms_hotness;
+        // SAFETY: rooms of a phase are a room apart and `reach` keeps each one
+        // within half a room of its cells, so no cell is accessed by two threads
+        let view = unsafe {
+            RoomView {
+                materials: &self.materials,
+                config: &self.config,
+                dirty_rects: &self.dirty_rects,
+                room_size: self.room_size,
+                water_dispersion: self.water_dispersion,
+                generation: self.generation,
+                grid: SharedGrid::new(&mut self.grid),
+                temperature: SharedGrid::new(&mut self.temperature),
+                moved: SharedGrid::new(&mut self.moved),
+                reacted: SharedGrid::new(&mut self.reacted),
+            }
+        };
+
+        for phase in 0..4 {
+            let mut rooms = vec![];
+            for room_x in (phase % 2..rooms_hotness.width()).step_by(2) {
+                for room_y in (phase / 2..rooms_hotness.height()).step_by(2) {
+                    if (*rooms_hotness.get(room_x, room_y) > 0 ) {
+                        rooms.push((room_x, room_y));
+                    }
                 }
-            }   
-        }
+            }
 
-        for cell in ret.iter() {
-            self.hot(cell.x, cell.y);
+            if !parallel || rooms.len() < 2 {
+                for (room_x, room_y) in rooms {
+                    ret.extend( process(&view, room_x, room_y, &mut room_rng(room_x, room_y)) );
+                }
+                continue;
+            }
+
+            let chunk_size = rooms.len().div_ceil(threads);
+            let results : Vec<Vec<Vector2<usize>>> = std::thread::scope(|scope| {
+                let handles : Vec<_> = rooms.chunks(chunk_size).map(|chunk| {
+                    let view = &view;
+                    let process = &process;
+                    let room_rng = &room_rng;
+                    scope.spawn(move || {
+                        let mut ret = vec![];
+                        for &(room_x, room_y) in chunk {
+                            ret.extend( process(view, room_x, room_y, &mut room_rng(room_x, room_y)) );
+                        }
+                        ret
+                    })
+                }).collect();
+                handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+            });
+            for changed in results {
+                ret.extend(changed);
+            }
         }
 
         return ret;
     }
 
+    // How far from its own cells processing a room may read or write
+    fn reach(&self) -> usize {
+        let explosion = self.materials.iter().map(|m| m.explosion_radius).max().unwrap_or(0);
+        [
+            self.config.max_fall_speed.ceil() as usize,
+            // aggressive slide
+            *self.config.slide_range.end(),
+            // rising gas drifting sideways
+            3,
+            // dispersing liquids look at the cell below the last one
+            self.water_dispersion + 1,
+            // next to a fire
+            explosion + 1,
+        ].into_iter().max().unwrap()
+    }
+
+}
+
+
+// What a room may use of the world while its phase runs: the cell grids,
+// shared with the other rooms of the phase, and the rest read-only
+struct RoomView<'a> {
+    materials: &'a Arc<MaterialRegistry>,
+    config: &'a WorldConfig,
+    dirty_rects: &'a GridMap<Option<DirtyRect>>,
+    room_size: Vector2<usize>,
+    water_dispersion: usize,
+    generation: u32,
+    grid: SharedGrid<'a, CellType>,
+    temperature: SharedGrid<'a, f32>,
+    moved: SharedGrid<'a, u32>,
+    reacted: SharedGrid<'a, u32>,
+}
+
+impl<'a> RoomView<'a> {
+
+    fn width(&self) -> usize {
+        self.grid.width()
+    }
+
+    fn height(&self) -> usize {
+        self.grid.height()
+    }
+
+    fn get(&self, x:usize, y:usize) -> CellType {
+        self.grid.get(x, y)
+    }
+
+    fn temperature(&self, x:usize, y:usize) -> f32 {
+        self.temperature.get(x, y)
+    }
+
+    fn density(&self, x:usize, y:usize) -> f32 {
+        self.materials.get(self.get(x, y).material()).density
+    }
+
     //fn process_room(&mut self, tmp_grid:&mut GridMap<CellType>, room_x:usize, room_y:usize) -> Vec<Vector2<usize>>  {
-    fn process_room(&mut self, room_x:usize, room_y:usize) -> Vec<Vector2<usize>>  {
+    fn process_room(&self, room_x:usize, room_y:usize, rng:&mut SimRng) -> Vec<Vector2<usize>>  {
         
         let mut ret = vec![];
         
+        // Nothing changed around here last frame, nothing will move
+        let rect = match self.dirty_rects.get(room_x, room_y) {
+            Some(rect) => *rect,
+            None => return ret,
+        };
+
         // Cloning cheap values
-        let room_size = self.room_size.clone();
         let height = self.height();
         let width = self.width();
+        let materials = self.materials.clone();
+        let air_density = materials.get(EMPTY).density;
+        let generation = self.generation;
+        let gravity = self.config.gravity;
+        let max_fall_speed = self.config.max_fall_speed;
+        let slide_range = self.config.slide_range.clone();
 
-        let mut xvec : Vec<usize> = (
-            (room_x*room_size.x)..min(width, (room_x+1)*room_size.x)
-        ).collect();
-        let mut yvec : Vec<usize> = (
-            (room_y*room_size.y)..min(height-1, (room_y+1)*room_size.y)
-        ).collect();
+        let mut xvec : Vec<usize> = (rect.left..=rect.right).collect();
+        let mut yvec : Vec<usize> = (rect.top..=rect.bottom).collect();
         
-        let mut rng = rand::thread_rng();
-        xvec.shuffle(&mut rng);
-        yvec.shuffle(&mut rng);
-        let mut dirty_cells = HashSet::<Vector2<usize>>::new();
+        xvec.shuffle(rng);
+        yvec.shuffle(rng);
 
         for x in xvec.iter() {
             for y in yvec.iter() {
                 let x = *x;
                 let y = *y;
-                if dirty_cells.contains(&Vector2{x: x, y: y}) {
+                if self.moved.get(x, y) == generation {
                     continue;
                 }
-                match self.get(x, y) {
-                    CellType::Empty => {},
-                    CellType::Sand(data) => {
+                let data = match self.get(x, y) {
+                    CellType::Empty => continue,
+                    CellType::Particle(data) => data.clone(),
+                };
+                let material = materials.get(data.material);
+                let density = material.density;
+
+                let movement = match material.state {
+                    State::Powder => {
 
-                        let mut new_pos : Option<Vector2<usize>> = None;
-                        let mut new_data = Option::<ParticleData>::None;
                         let data = ParticleData{
                             speed: Vector2 {
                                 x: data.speed.x,
-                                y: data.speed.y + 0.15,
+                                y: (data.speed.y + gravity).min(max_fall_speed),
                             },
-                            color: data.color,
-                        };
-                        let min_dy = if rng.gen_bool(0.5) {
-                            0
-                        } else {
-                            1
-                        };
-                        let max_dy = if rng.gen_bool(0.5) {
-                            data.speed.y.floor() as usize
-                        } else {
-                            data.speed.y.ceil() as usize
+                            ..data
                         };
-                        
-
-                        // Finding the next cell below we can go
-                        for dy in (min_dy..=max_dy) {
-                            // full speed and still empty? nice
-                            if (dy == max_dy) && (y+dy<height) && (*self.get(x, y + dy ) == CellType::Empty) {
-                                new_pos = Some(Vector2{x: x, y: y+dy});
-                                new_data = Some(data.clone());
-                                break;
-                            } 
-                            // hit something, lets check before
-                            else if (y + dy >= height) || (*self.get(x, y + dy ) != CellType::Empty) {
-                                // there was a before?
-                                if (dy>1) {
-                                    new_pos = Some(Vector2{x: x.clone(), y: y.clone()+dy-1});
-                                    new_data = Some(ParticleData{
-                                        speed: Vector2{
-                                            x:data.speed.x,
-                                            y:data.speed.y*0.1
-                                        },
-                                        color: data.color
-                                    });
-                                    break;
-                                } 
-                                // ugh, maybe we slide
-                                break
-                            }
-                        }
-
+                        let mut movement = self.fall(x, y, density, &data, rng);
 
                         // aggressive slide
-                        if new_pos.is_none() && y > 0 && y < height-1 {
-                            let rand_dx = rng.gen_range(2..=4);
-                            if (x > rand_dx) && (x < width-rand_dx) && *self.get(x,y+1) != CellType::Empty  && *self.get(x,y-1) != CellType::Empty {
+                        if movement.is_none() && y > 0 && y+1 < height {
+                            let rand_dx = rng.gen_range(slide_range.clone());
+                            let weighted = self.density(x, y-1) > air_density;
+                            if (x > rand_dx) && (x < width-rand_dx) && !self.is_lighter(x, y+1, density) && weighted {
                                 let mut fall_right =
-                                    (*self.get(x + rand_dx, y+1) == CellType::Empty) && 
-                                    (*self.get(x - rand_dx, y+1) != CellType::Empty);
+                                    self.is_lighter(x + rand_dx, y+1, density) && 
+                                    !self.is_lighter(x - rand_dx, y+1, density);
                                 let mut fall_left =
-                                    (*self.get(x - rand_dx, y+1) == CellType::Empty) && 
-                                    (*self.get(x + rand_dx, y+1) != CellType::Empty);
+                                    self.is_lighter(x - rand_dx, y+1, density) && 
+                                    !self.is_lighter(x + rand_dx, y+1, density);
 
                                 if fall_right {
-                                    new_pos = Some(Vector2{x:x+rand_dx, y: y+1});
-                                    new_data = Some(data.clone());
-                                    new_data.as_mut().unwrap().speed.y = 1.0;
+                                    let mut new_data = data.clone();
+                                    new_data.speed.y = 1.0;
+                                    movement = Some((Vector2{x:x+rand_dx, y: y+1}, new_data));
                                 } else if fall_left  {
-                                    new_pos = Some(Vector2{x:x-rand_dx, y: y+1});
-                                    new_data = Some(data.clone());
-                                    new_data.as_mut().unwrap().speed.y = 1.0;
+                                    let mut new_data = data.clone();
+                                    new_data.speed.y = 1.0;
+                                    movement = Some((Vector2{x:x-rand_dx, y: y+1}, new_data));
                                 }
                             }
                         }
 
                         // simple slide
-                        if new_pos.is_none() && (y + 1 < height) {
-                            let mut fall_right =
-                                (x < width-1) &&
-                                (*self.get(x + 1, y + 1) == CellType::Empty);
-                            let mut fall_left =
-                                (x > 0) &&
-                                (*self.get(x - 1, y + 1) == CellType::Empty);
-                            if (fall_left && fall_right) {
-                                fall_right = rng.gen_bool(0.5);
-                                fall_left = !fall_right;
-                            }
-                            if fall_right {
-                                new_pos = Some(Vector2{x:x+1, y:y+1});
-                                new_data = Some(data.clone());
-                            } else if fall_left  {
-                                new_pos = Some(Vector2{x:x-1, y:y+1});
-                                new_data = Some(data.clone());
-                            }
+                        if movement.is_none() {
+                            movement = self.slide(x, y, density, &data, rng);
                         }
 
-                        
-                        match new_pos {
-                            None => {},
-                            Some(new_pos) => {
-                                if dirty_cells.contains(&new_pos) {
-                                    continue;
-                                } else {
-                                    self.grid.set(x, y, CellType::Empty);
-                                    self.grid.set(new_pos.x, new_pos.y, CellType::Sand(new_data.unwrap()));
-                                    ret.push(Vector2{x: x, y: y});
-                                    ret.push(new_pos);
-                                    dirty_cells.insert(new_pos);
-                                }
-                            }
+                        movement
+                    },
+                    State::Liquid => {
+
+                        let data = ParticleData{
+                            speed: Vector2 {
+                                x: data.speed.x,
+                                y: (data.speed.y + gravity).min(max_fall_speed),
+                            },
+                            ..data
+                        };
+                        let mut movement = self.fall(x, y, density, &data, rng);
+
+                        if movement.is_none() {
+                            movement = self.slide(x, y, density, &data, rng);
                         }
+
+                        // can't go down, so spread sideways looking for our level
+                        if movement.is_none() {
+                            movement = self.disperse(x, y, density, &data, rng);
+                        }
+
+                        movement
                     },
-                    CellType::Block(_) => {},
+                    State::Gas => {
+                        if data.lifetime == 1 {
+                            let decayed = self.decay(data.material, rng);
+                            self.grid.set(x, y, decayed);
+                            ret.push(Vector2{x: x, y: y});
+                            self.moved.set(x, y, generation);
+                            continue;
+                        }
+
+                        let data = ParticleData{
+                            speed: Vector2 {
+                                x: data.speed.x,
+                                y: (data.speed.y - 0.1).max(-2.0),
+                            },
+                            lifetime: data.lifetime.saturating_sub(1),
+                            ..data
+                        };
+                        let movement = self.rise(x, y, density, &data, rng);
+
+                        // even a gas stuck in place is getting older, so keep its room awake
+                        if movement.is_none() && data.lifetime > 0 {
+                            self.grid.set(x, y, CellType::Particle(data.clone()));
+                            ret.push(Vector2{x: x, y: y});
+                        }
+
+                        movement
+                    },
+                    // static, fire is handled by the interactions pass
+                    State::Solid | State::Fire => None,
+                };
+
+                match movement {
+                    None => {
+                        // could still move but didn't this time, stays in the dirty rect
+                        let unsettled = match material.state {
+                            State::Powder | State::Liquid => (y+1 < height) && self.is_lighter(x, y+1, density),
+                            State::Gas => (data.lifetime == 0) && (y > 0) && self.is_heavier(x, y-1, density),
+                            State::Solid | State::Fire => false,
+                        };
+                        if unsettled {
+                            ret.push(Vector2{x: x, y: y});
+                        }
+                    },
+                    Some((new_pos, new_data)) => {
+                        if self.moved.get(new_pos.x, new_pos.y) == generation {
+                            // the way is taken for this frame, try again next one
+                            ret.push(Vector2{x: x, y: y});
+                            continue;
+                        } else {
+                            // new_pos holds something lighter than us (maybe just air),
+                            // swapping pushes it into the place we are leaving
+                            self.grid.swap(x, y, new_pos.x, new_pos.y);
+                            self.temperature.swap(x, y, new_pos.x, new_pos.y);
+                            self.grid.set(new_pos.x, new_pos.y, CellType::Particle(new_data));
+                            ret.push(Vector2{x: x, y: y});
+                            ret.push(new_pos);
+                            self.moved.set(x, y, generation);
+                            self.moved.set(new_pos.x, new_pos.y, generation);
+                        }
+                    }
                 }
             
             }
         }
 
+        // Reactions with the neighbours, after everyone had the chance to move
+        for x in xvec.iter() {
+            for y in yvec.iter() {
+                self.react(*x, *y, rng, &mut ret);
+            }
+        }
+
+        return ret;
+    }
+
+    // Tries the registry's reactions for the cell at (x, y) against its 4 neighbours,
+    // each cell takes part in one reaction per frame at most
+    fn react(&self, x:usize, y:usize, rng:&mut SimRng, changed:&mut Vec<Vector2<usize>>) {
+        let generation = self.generation;
+        if self.reacted.get(x, y) == generation {
+            return;
+        }
+        let materials = self.materials.clone();
+        let reactions = materials.reactions(self.get(x, y).material());
+        if reactions.is_empty() {
+            return;
+        }
+
+        let mut neighbours = vec![];
+        if x > 0 { neighbours.push(Vector2{x: x-1, y: y}); }
+        if x+1 < self.width() { neighbours.push(Vector2{x: x+1, y: y}); }
+        if y > 0 { neighbours.push(Vector2{x: x, y: y-1}); }
+        if y+1 < self.height() { neighbours.push(Vector2{x: x, y: y+1}); }
+        neighbours.shuffle(rng);
+
+        let mut possible = false;
+        for other in neighbours.iter() {
+            if self.reacted.get(other.x, other.y) == generation {
+                continue;
+            }
+            let other_material = self.get(other.x, other.y).material();
+            for reaction in reactions.iter() {
+                if !reaction.b.matches(other_material) {
+                    continue;
+                }
+                possible = true;
+                if rng.gen::<f32>() < reaction.chance {
+                    self.place(x, y, materials.get(reaction.into_a).spawn(rng));
+                    self.place(other.x, other.y, materials.get(reaction.into_b).spawn(rng));
+                    self.reacted.set(x, y, generation);
+                    self.reacted.set(other.x, other.y, generation);
+                    changed.push(Vector2{x: x, y: y});
+                    changed.push(*other);
+                    return;
+                }
+            }
+        }
+
+        // unlucky this time, keep trying on the next frames like a stuck gas does
+        if possible {
+            changed.push(Vector2{x: x, y: y});
+        }
+    }
+
+    // `set` for the simulation itself: the room is woken up through the changed
+    // cells, and the change is nobody's to undo
+    fn place(&self, x:usize, y:usize, cell:CellType) {
+        self.temperature.set(x, y, self.materials.get(cell.material()).temperature);
+        self.grid.set(x, y, cell);
+    }
+
+    // Whether the cell at (x, y) can be displaced by something of the given density
+    fn is_lighter(&self, x:usize, y:usize, density:f32) -> bool {
+        self.density(x, y) < density
+    }
+
+    // What a particle leaves behind once its lifetime runs out
+    fn decay(&self, material:MaterialId, rng:&mut SimRng) -> CellType {
+        match self.materials.get(material).decays_into {
+            Some(product) if rng.gen::<f32>() < product.chance => {
+                self.materials.get(product.material).spawn(rng)
+            },
+            _ => CellType::Empty,
+        }
+    }
+
+    // Cells affecting their neighbours instead of just moving around: for now
+    // fire spreading to anything flammable, giving off smoke and burning out
+    fn process_interactions(&self, room_x:usize, room_y:usize, rng:&mut SimRng) -> Vec<Vector2<usize>> {
+
+        let mut ret = vec![];
+
+        // Burning cells change every frame, so they're always in the dirty rect
+        let rect = match self.dirty_rects.get(room_x, room_y) {
+            Some(rect) => *rect,
+            None => return ret,
+        };
+
+        // Collecting first so that fire started during this pass only burns next frame
+        let mut fires = vec![];
+        for x in (rect.left..=rect.right) {
+            for y in (rect.top..=rect.bottom) {
+                if self.materials.get(self.get(x, y).material()).state == State::Fire {
+                    fires.push(Vector2{x: x, y: y});
+                }
+            }
+        }
+
+        for pos in fires.iter() {
+            self.burn(pos.x, pos.y, rng, &mut ret);
+        }
+
         return ret;
     }
 
+    fn burn(&self, x:usize, y:usize, rng:&mut SimRng, changed:&mut Vec<Vector2<usize>>) {
+        let materials = self.materials.clone();
+
+        // an explosion nearby may have already replaced us
+        let data = match self.get(x, y) {
+            CellType::Particle(data) if materials.get(data.material).state == State::Fire => data.clone(),
+            _ => return,
+        };
+        let material = materials.get(data.material);
+
+        if data.lifetime == 1 {
+            let leftover = self.decay(data.material, rng);
+            self.grid.set(x, y, leftover);
+            changed.push(Vector2{x: x, y: y});
+            return;
+        }
+
+        // spreading to the 8 neighbours
+        for nx in (max(1, x)-1..=min(x+1, self.width()-1)) {
+            for ny in (max(1, y)-1..=min(y+1, self.height()-1)) {
+                let flammability = materials.get(self.get(nx, ny).material()).flammability;
+                if (flammability > 0.0) && rng.gen_bool(flammability as f64) {
+                    self.ignite(nx, ny, rng, changed);
+                }
+            }
+        }
+
+        // smoke goes out the top
+        if let Some(emits) = material.emits {
+            if (y > 0) && (self.get(x, y-1) == CellType::Empty) && (rng.gen::<f32>() < emits.chance) {
+                self.grid.set(x, y-1, materials.get(emits.material).spawn(rng));
+                changed.push(Vector2{x: x, y: y-1});
+            }
+        }
+
+        // aging and flickering, while keeping the heat up
+        if self.get(x, y).material() == data.material {
+            let mut flicker = material.spawn(rng);
+            if let Some(new_data) = flicker.data_mut() {
+                new_data.lifetime = data.lifetime.saturating_sub(1);
+            }
+            self.grid.set(x, y, flicker);
+            if self.temperature(x, y) < material.temperature {
+                self.temperature.set(x, y, material.temperature);
+            }
+            changed.push(Vector2{x: x, y: y});
+        }
+    }
+
+    fn ignite(&self, x:usize, y:usize, rng:&mut SimRng, changed:&mut Vec<Vector2<usize>>) {
+        let materials = self.materials.clone();
+        let material = materials.get(self.get(x, y).material());
+        if material.explosion_radius > 0 {
+            self.explode(x, y, material.explosion_radius, rng, changed);
+        } else {
+            let product = materials.get(material.burns_into);
+            let mut fire = product.spawn(rng);
+            if let Some(data) = fire.data_mut() {
+                data.lifetime = material.burn_time;
+            }
+            let temperature = self.temperature(x, y).max(product.temperature);
+            self.grid.set(x, y, fire);
+            self.temperature.set(x, y, temperature);
+            changed.push(Vector2{x: x, y: y});
+        }
+    }
+
+    // Diffuses heat inside the room and applies the phase changes it causes.
+    // Returns the changed cells, and whether temperatures are still moving so the
+    // room is kept awake until they settle.
+    fn process_temperature(&self, room_x:usize, room_y:usize, previous:&GridMap<f32>, rng:&mut SimRng) -> (Vec<Vector2<usize>>, bool) {
+
+        let mut ret = vec![];
+
+        let room_size = self.room_size.clone();
+        let height = self.height();
+        let width = self.width();
+        let materials = self.materials.clone();
+        let mut warming = false;
+
+        for x in ((room_x*room_size.x)..min(width, (room_x+1)*room_size.x)) {
+            for y in ((room_y*room_size.y)..min(height, (room_y+1)*room_size.y)) {
+                let t = *previous.get(x, y);
+                let mut sum = 0.0;
+                let mut count = 0.0;
+                if x > 0 { sum += previous.get(x-1, y); count += 1.0; }
+                if x+1 < width { sum += previous.get(x+1, y); count += 1.0; }
+                if y > 0 { sum += previous.get(x, y-1); count += 1.0; }
+                if y+1 < height { sum += previous.get(x, y+1); count += 1.0; }
+
+                let material = materials.get(self.get(x, y).material());
+                let mut new_t = t + material.conductivity * (sum/count - t);
+                // open air slowly loses its heat to the surroundings
+                if material.id == EMPTY {
+                    new_t += 0.01 * (AMBIENT_TEMPERATURE - new_t);
+                }
+                if (new_t - t).abs() > 0.05 {
+                    warming = true;
+                }
+                self.temperature.set(x, y, new_t);
+
+                if (material.flammability > 0.0) && (new_t >= material.ignition_point) {
+                    self.ignite(x, y, rng, &mut ret);
+                } else if let Some(change) = material.heated.filter(|c| new_t >= c.temperature)
+                    .or(material.cooled.filter(|c| new_t < c.temperature)) {
+                    self.grid.set(x, y, materials.get(change.into).spawn(rng));
+                    ret.push(Vector2{x: x, y: y});
+                }
+            }
+        }
+
+        return (ret, warming);
+    }
+
+    // Everything but solids inside the radius is blown away, leaving short lived fire
+    // behind. Other explosives caught in it will chain on the next frame.
+    fn explode(&self, cx:usize, cy:usize, radius:usize, rng:&mut SimRng, changed:&mut Vec<Vector2<usize>>) {
+        let materials = self.materials.clone();
+        let blast = materials.get(materials.get(self.get(cx, cy).material()).burns_into);
+        let r2 = (radius*radius) as i32;
+        for x in (max(radius, cx)-radius..=min(cx+radius, self.width()-1)) {
+            for y in (max(radius, cy)-radius..=min(cy+radius, self.height()-1)) {
+                let dx = x as i32 - cx as i32;
+                let dy = y as i32 - cy as i32;
+                if (dx*dx + dy*dy > r2) || !self.density(x, y).is_finite() {
+                    continue;
+                }
+                if (materials.get(self.get(x, y).material()).explosion_radius > 0) && ((x != cx) || (y != cy)) {
+                    continue;
+                }
+                let cell = if rng.gen_bool(0.5) {
+                    let mut fire = blast.spawn(rng);
+                    if let Some(data) = fire.data_mut() {
+                        data.lifetime = rng.gen_range(5..=15);
+                    }
+                    fire
+                } else {
+                    CellType::Empty
+                };
+                self.temperature.set(x, y, materials.get(cell.material()).temperature);
+                self.grid.set(x, y, cell);
+                changed.push(Vector2{x: x, y: y});
+            }
+        }
+    }
+
+    // Finding the next cell below we can go, according to the particle speed
+    fn fall(&self, x:usize, y:usize, density:f32, data:&ParticleData, rng:&mut SimRng) -> Option<(Vector2<usize>, ParticleData)> {
+        let height = self.height();
+        let air_density = self.materials.get(EMPTY).density;
+        let min_dy = if rng.gen_bool(0.5) {
+            0
+        } else {
+            1
+        };
+        let max_dy = if rng.gen_bool(0.5) {
+            data.speed.y.floor() as usize
+        } else {
+            data.speed.y.ceil() as usize
+        };
+
+        for dy in (min_dy..=max_dy) {
+            // sinking through something heavier than air, it slows us down
+            if (dy > 0) && (y+dy<height) && self.is_lighter(x, y + dy, density)
+                && (self.density(x, y + dy) > air_density) {
+                return Some((
+                    Vector2{x: x, y: y+dy},
+                    ParticleData{
+                        speed: Vector2{
+                            x:data.speed.x,
+                            y:0.5
+                        },
+                        ..data.clone()
+                    },
+                ));
+            }
+            // full speed and still free? nice
+            else if (dy == max_dy) && (y+dy<height) && self.is_lighter(x, y + dy, density) {
+                return Some((Vector2{x: x, y: y+dy}, data.clone()));
+            } 
+            // hit something, lets check before
+            else if (y + dy >= height) || !self.is_lighter(x, y + dy, density) {
+                // there was a before?
+                if (dy>1) {
+                    return Some((
+                        Vector2{x: x, y: y+dy-1},
+                        ParticleData{
+                            speed: Vector2{
+                                x:data.speed.x,
+                                y:data.speed.y*0.1
+                            },
+                            ..data.clone()
+                        },
+                    ));
+                } 
+                // ugh, maybe we slide
+                break
+            }
+        }
+        return None;
+    }
+
+    // Whether the cell at (x, y) is heavier than the given density and free to be pushed around
+    fn is_heavier(&self, x:usize, y:usize, density:f32) -> bool {
+        let other = self.density(x, y);
+        other > density && other.is_finite()
+    }
+
+    // Gases mirror `fall`, negative speed.y takes them up while drifting sideways at random
+    fn rise(&self, x:usize, y:usize, density:f32, data:&ParticleData, rng:&mut SimRng) -> Option<(Vector2<usize>, ParticleData)> {
+        let width = self.width() as i32;
+        let air_density = self.materials.get(EMPTY).density;
+        let steps = (-data.speed.y).ceil().max(1.0) as usize;
+        let mut pos = Vector2{x: x, y: y};
+
+        for _ in (0..steps) {
+            if pos.y == 0 {
+                break;
+            }
+            let nx = pos.x as i32 + rng.gen_range(-1..=1);
+            if (nx < 0) || (nx >= width) || !self.is_heavier(nx as usize, pos.y - 1, density) {
+                break;
+            }
+            pos = Vector2{x: nx as usize, y: pos.y - 1};
+            // pushing through something denser than air stops us for this tick
+            if self.density(pos.x, pos.y) > air_density {
+                break;
+            }
+        }
+
+        // nowhere to go up, wander sideways under the ceiling
+        if (pos.x == x) && (pos.y == y) {
+            let nx = x as i32 + if rng.gen_bool(0.5) { 1 } else { -1 };
+            if (nx >= 0) && (nx < width) && self.is_heavier(nx as usize, y, density) {
+                pos = Vector2{x: nx as usize, y: y};
+            }
+        }
+
+        if (pos.x == x) && (pos.y == y) {
+            return None;
+        }
+        return Some((pos, data.clone()));
+    }
+
+    // One cell down to the left or to the right, whichever is free
+    fn slide(&self, x:usize, y:usize, density:f32, data:&ParticleData, rng:&mut SimRng) -> Option<(Vector2<usize>, ParticleData)> {
+        let height = self.height();
+        let width = self.width();
+        if (y + 1 >= height) {
+            return None;
+        }
+        let mut fall_right =
+            (x < width-1) &&
+            self.is_lighter(x + 1, y + 1, density);
+        let mut fall_left =
+            (x > 0) &&
+            self.is_lighter(x - 1, y + 1, density);
+        if (fall_left && fall_right) {
+            fall_right = rng.gen_bool(0.5);
+            fall_left = !fall_right;
+        }
+        if fall_right {
+            return Some((Vector2{x:x+1, y:y+1}, data.clone()));
+        } else if fall_left  {
+            return Some((Vector2{x:x-1, y:y+1}, data.clone()));
+        }
+        return None;
+    }
+
+    // Liquids walk sideways up to `water_dispersion` cells, keeping the direction
+    // they were already flowing (speed.x) so they don't jitter in place
+    fn disperse(&self, x:usize, y:usize, density:f32, data:&ParticleData, rng:&mut SimRng) -> Option<(Vector2<usize>, ParticleData)> {
+        let height = self.height();
+        let width = self.width() as i32;
+        let air_density = self.materials.get(EMPTY).density;
+        let first_dir : i32 = if data.speed.x > 0.0 {
+            1
+        } else if data.speed.x < 0.0 {
+            -1
+        } else if rng.gen_bool(0.5) {
+            1
+        } else {
+            -1
+        };
+
+        for dir in [first_dir, -first_dir] {
+            let mut reached = None;
+            for step in (1..=self.water_dispersion as i32) {
+                let nx = x as i32 + dir*step;
+                if (nx < 0) || (nx >= width) || !self.is_lighter(nx as usize, y, density) {
+                    break;
+                }
+                reached = Some(nx as usize);
+                // pushing something aside, that's as far as we go
+                if self.density(nx as usize, y) > air_density {
+                    break;
+                }
+                // found a hole, stop above it so we fall next tick
+                if (y + 1 < height) && self.is_lighter(nx as usize, y + 1, density) {
+                    break;
+                }
+            }
+            if let Some(nx) = reached {
+                return Some((
+                    Vector2{x: nx, y: y},
+                    ParticleData{
+                        speed: Vector2{x: dir as f32, y: 1.0},
+                        ..data.clone()
+                    },
+                ));
+            }
+        }
+        return None;
+    }
+
+}
+
+
+// Walks through a snapshot, failing cleanly on truncated files
+struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let ret = self.bytes.get(self.pos..self.pos + len).ok_or("truncated snapshot".to_string())?;
+        self.pos += len;
+        return Ok(ret);
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+}
+
+
+// Cells of a room to look at, bounds included
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct DirtyRect {
+    left: usize,
+    top: usize,
+    right: usize,
+    bottom: usize,
+}
+
+impl DirtyRect {
+    fn include(&mut self, x: usize, y: usize) {
+        self.left = min(self.left, x);
+        self.top = min(self.top, y);
+        self.right = max(self.right, x);
+        self.bottom = max(self.bottom, y);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A strip of every kind of behaviour, falling, flowing, burning, melting and dissolving
+    fn busy_world(width: usize, height: usize, config: WorldConfig, materials: &Arc<MaterialRegistry>) -> World {
+        let mut world = World::with_seed(width, height, 11, config, materials.clone());
+        let names = ["sand", "water", "oil", "fire", "gunpowder", "wood", "ice", "lava", "acid", "salt", "steam"];
+        let strip = width / names.len();
+        for (i, name) in names.iter().enumerate() {
+            let id = materials.id(name).unwrap();
+            for x in 0..strip*6/7 {
+                for y in height/10..height/2 + (i%3)*height/8 {
+                    let cell = materials.get(id).spawn(world.rng());
+                    world.set(i*strip + x, y, cell);
+                }
+            }
+        }
+        return world;
+    }
+
+    fn snapshot(world: &World) -> Vec<u8> {
+        let mut bytes = vec![];
+        world.save(&mut bytes).unwrap();
+        return bytes;
+    }
+
+    // Same seed, same scene: the grids can't depend on how many threads ran the rooms
+    #[test]
+    fn threads_do_not_change_the_result() {
+        let materials = Arc::new(MaterialRegistry::load("assets/materials.txt").unwrap());
+        let config = WorldConfig { rooms: RoomLayout::Size(Vector2 { x: 32, y: 32 }), ..WorldConfig::default() };
+        let mut serial = busy_world(320, 256, config.clone(), &materials);
+        let mut parallel = busy_world(320, 256, config.clone(), &materials);
+        serial.set_threads(1);
+        parallel.set_threads(4);
+        let room_size = parallel.room_size();
+        assert!(2 * parallel.reach() <= min(room_size.x, room_size.y), "rooms too small to run in parallel");
+
+        for _ in 0..100 {
+            serial.process_frame();
+            parallel.process_frame();
+        }
+
+        assert!(snapshot(&serial) == snapshot(&parallel), "1 and 4 threads gave different worlds");
+    }
+
+    // Mid-simulation, so rooms, dirty rects and temperatures all have something to save
+    #[test]
+    fn snapshots_load_back_identical() {
+        let materials = Arc::new(MaterialRegistry::load("assets/materials.txt").unwrap());
+        let config = WorldConfig { rooms: RoomLayout::Size(Vector2 { x: 12, y: 10 }), gravity: 0.2, ..WorldConfig::default() };
+        let mut world = busy_world(110, 60, config, &materials);
+        for _ in 0..30 {
+            world.process_frame();
+        }
+
+        let bytes = snapshot(&world);
+        let mut loaded = World::load(&bytes[..], materials.clone()).unwrap();
+        assert!(snapshot(&loaded) == bytes, "saving a loaded snapshot changed it");
+
+        // and it carries on as if it had never been saved
+        for _ in 0..30 {
+            world.process_frame();
+            loaded.process_frame();
+        }
+        assert!(snapshot(&loaded) == snapshot(&world), "the loaded world went its own way");
+    }
+
+    #[test]
+    fn broken_snapshots_are_refused() {
+        let materials = Arc::new(MaterialRegistry::load("assets/materials.txt").unwrap());
+        let mut world = busy_world(44, 30, WorldConfig::default(), &materials);
+        for _ in 0..10 {
+            world.process_frame();
+        }
+        let bytes = snapshot(&world);
+
+        for len in 0..bytes.len() {
+            assert!(World::load(&bytes[..len], materials.clone()).is_err(), "loaded a snapshot cut at {} bytes", len);
+        }
+
+        let corrupt = |at: usize, value: &[u8]| {
+            let mut bytes = bytes.clone();
+            bytes[at..at + value.len()].copy_from_slice(value);
+            World::load(&bytes[..], materials.clone())
+        };
+        assert!(corrupt(0, b"XXXX").is_err(), "loaded without the magic");
+        assert!(corrupt(4, &(SNAPSHOT_VERSION + 1).to_le_bytes()).is_err(), "loaded a newer version");
+        assert!(corrupt(6, &0u32.to_le_bytes()).is_err(), "loaded an empty world");
+        assert!(corrupt(6, &u32::MAX.to_le_bytes()).is_err(), "loaded a world too big to hold");
+    }
 }